@@ -22,12 +22,19 @@ in {
       wants = [ "network-online.target" ];
       wantedBy = [ "multi-user.target" ];
 
+      # used for transcoding voice messages and converting stickers
+      path = [ pkgs.ffmpeg pkgs.lottieconverter ];
+
       serviceConfig = {
         Type = "simple";
         ExecStart = lib.getExe package;
         Restart = "on-failure";
         DynamicUser = true;
 
+        # the state file lives in the working directory
+        StateDirectory = "oxibridge";
+        WorkingDirectory = "/var/lib/oxibridge";
+
         LoadCredential = "config-file:${cfg.configFile}";
 
         NoNewPrivileges = true;
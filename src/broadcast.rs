@@ -1,8 +1,12 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use crate::{config::GroupConfig, core::Message};
+use crate::{
+    config::GroupConfig,
+    core::{Message, Poll},
+};
 use color_eyre::Result;
 use serenity::async_trait;
+use tokio::sync::Mutex;
 use tracing::*;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -12,19 +16,74 @@ pub enum Source {
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum MessageEvent {
     Create(Message),
-    Update(u64, String),
+    /// An edited message, parsed again in full. Its ID is the one of the original message.
+    Update(Message),
     Delete(u64),
+    /// New vote counts or state for the poll in a message.
+    PollUpdate(u64, Poll),
+    /// A topic was created or renamed. Receivers find its name and mappings in the state.
+    TopicUpdate(u64),
+}
+
+/// How long a message has to go without further edits before the last one is bridged.
+const EDIT_QUIET_PERIOD: Duration = Duration::from_millis(1500);
+
+/// Core message IDs with edits waiting out the quiet period, and the number of the latest one.
+#[derive(Clone, Default)]
+pub struct PendingEdits(Arc<Mutex<PendingEditsInner>>);
+
+#[derive(Default)]
+struct PendingEditsInner {
+    next: u64,
+    edits: HashMap<u64, u64>,
+}
+
+impl PendingEdits {
+    /// Waits until a message has gone without edits for the quiet period, before any parsing or
+    /// downloads. Returns the number of this edit, or `None` if a newer edit or a deletion took over.
+    pub async fn settle(&self, id: u64) -> Option<u64> {
+        let edit = {
+            let mut pending = self.0.lock().await;
+            let edit = pending.next;
+            pending.next += 1;
+            pending.edits.insert(id, edit);
+            edit
+        };
+
+        tokio::time::sleep(EDIT_QUIET_PERIOD).await;
+
+        (self.0.lock().await.edits.get(&id) == Some(&edit)).then_some(edit)
+    }
+
+    /// Takes the edit out of the pending ones if it is still the latest for its message.
+    async fn finish(&self, id: u64, edit: u64) -> bool {
+        let mut pending = self.0.lock().await;
+        if pending.edits.get(&id) != Some(&edit) {
+            return false;
+        }
+        pending.edits.remove(&id);
+        true
+    }
+
+    async fn cancel(&self, id: u64) {
+        self.0.lock().await.edits.remove(&id);
+    }
 }
 
 pub struct Broadcaster {
     sources: Vec<Arc<dyn BroadcastReceiver>>,
+    pending_edits: PendingEdits,
 }
 
 impl Broadcaster {
     pub fn init() -> Self {
-        Self { sources: vec![] }
+        Self {
+            sources: vec![],
+            pending_edits: PendingEdits::default(),
+        }
     }
 
     pub fn add_receiver(&mut self, receiver: Arc<dyn BroadcastReceiver>) -> &mut Self {
@@ -32,6 +91,11 @@ impl Broadcaster {
         self
     }
 
+    /// Handle for waiting out the quiet period without holding the broadcaster.
+    pub fn pending_edits(&self) -> PendingEdits {
+        self.pending_edits.clone()
+    }
+
     #[instrument(skip_all)]
     pub async fn broadcast(
         &self,
@@ -40,6 +104,11 @@ impl Broadcaster {
         source: Source,
     ) -> Result<()> {
         debug!(?group, ?event, ?source, "broadcasting message");
+        // an edit still waiting would otherwise arrive after the deletion
+        if let MessageEvent::Delete(id) = event {
+            self.pending_edits.cancel(*id).await;
+        }
+
         for receiver in &self.sources {
             if receiver.get_receiver_source() != source {
                 debug!("sending to {:?}", receiver.get_receiver_source());
@@ -49,6 +118,30 @@ impl Broadcaster {
 
         Ok(())
     }
+
+    /// Broadcasts an edit that was settled with [`PendingEdits::settle`], unless a newer edit or a
+    /// deletion came in while it was being parsed.
+    #[instrument(skip_all)]
+    pub async fn broadcast_edit(
+        &self,
+        group: &GroupConfig,
+        edit: u64,
+        event: MessageEvent,
+        source: Source,
+    ) -> Result<()> {
+        let id = match &event {
+            MessageEvent::Update(message) => message.id,
+            MessageEvent::PollUpdate(id, _) => *id,
+            _ => return self.broadcast(group, &event, source).await,
+        };
+
+        if !self.pending_edits.finish(id, edit).await {
+            debug!(id, "edit was superseded while parsing");
+            return Ok(());
+        }
+
+        self.broadcast(group, &event, source).await
+    }
 }
 
 #[async_trait]
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::core;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub shared: SharedConfig,
@@ -10,9 +12,57 @@ pub struct Config {
 pub struct SharedConfig {
     pub discord_token: Option<String>,
     pub telegram_token: Option<String>,
+    /// URL of a self-hosted Bot API server to use instead of `api.telegram.org`.
+    pub telegram_api_url: Option<String>,
+    /// Whether the Bot API server runs with `--local`, serving files from its local filesystem.
+    #[serde(default)]
+    pub telegram_local_mode: bool,
+    /// Where shared locations link to.
+    #[serde(default)]
+    pub map_provider: MapProvider,
+    /// Where to keep state that has to survive restarts, like topic mappings.
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    /// How Discord timestamps are written out for Telegram.
+    #[serde(default)]
+    pub timestamps: TimestampConfig,
     pub r2: Option<R2Config>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MapProvider {
+    #[default]
+    Google,
+    OpenStreetMap,
+}
+
+impl MapProvider {
+    /// Links to a point on the map, or to a specific place if the provider knows its ID.
+    pub fn url(self, latitude: f64, longitude: f64, google_place_id: Option<&str>) -> String {
+        match (self, google_place_id) {
+            (Self::Google, Some(id)) => format!(
+                "https://www.google.com/maps/search/?api=1&query={latitude}%2C{longitude}&query_place_id={id}"
+            ),
+            (Self::Google, None) => format!(
+                "https://www.google.com/maps/search/?api=1&query={latitude}%2C{longitude}"
+            ),
+            (Self::OpenStreetMap, _) => format!(
+                "https://www.openstreetmap.org/?mlat={latitude}&mlon={longitude}#map=16/{latitude}/{longitude}"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct TimestampConfig {
+    /// Offset from UTC to show times in, like `+02:00`. Defaults to UTC.
+    pub utc_offset: Option<String>,
+    /// `strftime` format to use instead of the one matching each timestamp's style. Relative
+    /// timestamps are always written out relative to when the message was bridged.
+    pub format: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct R2Config {
     pub bucket_name: String,
@@ -24,11 +74,206 @@ pub struct R2Config {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GroupConfig {
     pub telegram_chat: Option<i64>,
+    /// Largest file (in bytes) to upload to Telegram. Capped by the Bot API's own limit.
+    pub telegram_max_file_size: Option<u64>,
+    /// Whether to convert animated and video stickers from Telegram into GIFs.
+    #[serde(default = "default_true")]
+    pub convert_stickers: bool,
+    /// Whether to bridge each topic of a Telegram forum into its own Discord thread, or forum
+    /// post if the channel is a forum.
+    #[serde(default)]
+    pub forum_topics: bool,
+    /// How edited messages show up on the other platforms.
+    #[serde(default)]
+    pub edits: EditMode,
+    /// Whether deleted messages are struck through on the other platforms instead of deleted.
+    #[serde(default)]
+    pub soft_delete: bool,
+    /// Whether Telegram custom emoji without a Discord counterpart are attached as images.
+    #[serde(default)]
+    pub custom_emoji_images: bool,
+    /// How the names and headers added to bridged messages look.
+    #[serde(default)]
+    pub templates: Templates,
     pub discord: Option<GroupDiscordConfig>,
 }
 
+/// Formats for what the bridge writes around messages, with values filled in for `{placeholders}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Templates {
+    /// Author names, with `{name}`, `{username}`, `{source}` ("dc" or "tg") and `{platform}`.
+    /// Authors without a display name use their username as `{name}`, or just their username if
+    /// it would show up twice.
+    pub author: String,
+    /// The line above messages on Telegram, with `{author}`.
+    pub header: String,
+    /// The line above replies on Discord, with `{author}` and `{link}`.
+    pub reply: String,
+    /// The line above forwarded messages, with `{origin}`.
+    pub forward: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            author: "{name} (@{source}/{username})".to_owned(),
+            header: "**{author}**".to_owned(),
+            reply: "*In reply to {author} ({link})*".to_owned(),
+            forward: "*Forwarded from {origin}*".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EditMode {
+    /// Replaces the content without a trace.
+    #[default]
+    Replace,
+    /// Replaces the content and marks the message as edited.
+    Mark,
+    /// Keeps the earlier versions of the message below the current one.
+    History,
+}
+
+/// Most earlier versions kept for [`EditMode::History`], the oldest ones are dropped first.
+const HISTORY_LIMIT: usize = 5;
+
+impl EditMode {
+    /// The mode to use for a kind of content. Live locations get edited with every position
+    /// update, which aren't edits by the user, so they are always replaced.
+    pub fn for_kind(self, kind: &core::ContentKind) -> Self {
+        match kind {
+            core::ContentKind::Location(_) => Self::Replace,
+            _ => self,
+        }
+    }
+
+    /// Adds a new version of a message, dropping the ones this mode won't render anymore.
+    pub fn record(self, versions: &mut Vec<String>, content: &str) {
+        versions.push(content.to_owned());
+        let keep = match self {
+            Self::Replace | Self::Mark => 1,
+            Self::History => HISTORY_LIMIT + 1,
+        };
+        versions.drain(..versions.len().saturating_sub(keep));
+    }
+
+    /// Formats the content of an edited message from all of its versions, oldest first.
+    pub fn render(self, versions: &[String]) -> String {
+        let current = versions.last().map_or("", String::as_str);
+        match self {
+            Self::Replace => current.to_owned(),
+            Self::Mark => format!("{current} *(edited)*").trim().to_owned(),
+            Self::History => {
+                let earlier: Vec<_> = versions
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .filter(|version| !version.trim().is_empty())
+                    .map(|version| core::strike(version))
+                    .collect();
+                match earlier.is_empty() {
+                    true => current.to_owned(),
+                    false => format!("{current}\n*Edited, previously:*\n{}", earlier.join("\n")),
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GroupDiscordConfig {
     pub channel: u64,
     pub webhook: String,
+    /// Largest file (in bytes) to upload to Discord. Capped by the guild's boost level limit.
+    pub max_file_size: Option<u64>,
+    /// Telegram custom emoji and the guild's emoji they become, and the other way around.
+    #[serde(default)]
+    pub custom_emoji: Vec<EmojiMapping>,
+    /// Which mentions in bridged messages can ping people.
+    #[serde(default)]
+    pub mentions: MentionPolicy,
+}
+
+/// Which mentions in bridged messages can ping people on Discord. The others are shown as text.
+///
+/// Nothing pings by default, except replies to Discord messages pinging their author.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MentionPolicy {
+    /// Whether users can be pinged, only the ones in `allowed_users` if it isn't empty.
+    pub users: bool,
+    pub allowed_users: Vec<u64>,
+    /// Whether roles can be pinged, only the ones in `allowed_roles` if it isn't empty.
+    pub roles: bool,
+    pub allowed_roles: Vec<u64>,
+    /// Whether `@everyone` and `@here` can ping.
+    pub everyone: bool,
+}
+
+impl MentionPolicy {
+    pub fn allows_user(&self, id: u64) -> bool {
+        self.users && (self.allowed_users.is_empty() || self.allowed_users.contains(&id))
+    }
+
+    pub fn allows_role(&self, id: u64) -> bool {
+        self.roles && (self.allowed_roles.is_empty() || self.allowed_roles.contains(&id))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmojiMapping {
+    /// ID of the Telegram custom emoji.
+    pub telegram: String,
+    /// The Discord emoji, written as `<:name:id>`.
+    pub discord: String,
+    /// The regular emoji the Telegram one is based on, which Telegram needs alongside it.
+    pub emoji: String,
+}
+
+impl EmojiMapping {
+    /// ID of the Discord emoji.
+    pub fn discord_id(&self) -> Option<&str> {
+        self.discord.trim_end_matches('>').rsplit(':').next()
+    }
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+fn default_state_file() -> String {
+    "state.json".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_history() {
+        let versions = ["first".to_owned(), "".to_owned(), "third".to_owned()];
+        assert_eq!(EditMode::Replace.render(&versions), "third");
+        assert_eq!(EditMode::Mark.render(&versions), "third *(edited)*");
+        assert_eq!(
+            EditMode::History.render(&versions),
+            "third\n*Edited, previously:*\n~~first~~"
+        );
+        assert_eq!(EditMode::History.render(&versions[..1]), "first");
+    }
+
+    #[test]
+    fn edit_versions_are_capped() {
+        let mut versions = vec![];
+        for version in 0..10 {
+            EditMode::History.record(&mut versions, &version.to_string());
+        }
+        assert_eq!(versions.len(), HISTORY_LIMIT + 1);
+        assert_eq!(versions.last().map(String::as_str), Some("9"));
+
+        EditMode::Mark.record(&mut versions, "10");
+        assert_eq!(versions, ["10"]);
+    }
 }
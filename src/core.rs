@@ -1,9 +1,21 @@
-use std::sync::LazyLock;
+use std::{
+    path::Path,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
 use async_tempfile::TempFile;
-use tokio::sync::Mutex;
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use regex::{Captures, Regex};
+use tokio::{io::AsyncReadExt, sync::Mutex};
 
-use crate::broadcast::Source;
+use crate::{
+    broadcast::Source,
+    config::MapProvider,
+    media::{self, MediaType},
+    storage::R2Storage,
+};
 
 #[derive(Debug)]
 pub struct Author {
@@ -14,24 +26,37 @@ pub struct Author {
 }
 
 impl Author {
-    pub fn full_name(&self, length: Option<usize>) -> String {
-        let length = length.unwrap_or(32);
-
-        let source: &str= match self.source {
-            Source::Discord => "dc",
-            Source::Telegram => "tg",
+    /// Formats the author's name with a template, or gives just their name if that's longer than
+    /// `length` characters. A `length` of 0 means no limit. Authors without a display name go by
+    /// their username.
+    pub fn full_name(&self, template: &str, length: usize) -> String {
+        let (source, platform) = match self.source {
+            Source::Discord => ("dc", "Discord"),
+            Source::Telegram => ("tg", "Telegram"),
+        };
+        let name = match self.display_name.as_deref() {
+            Some(name) => name,
+            // the username would show up twice
+            None if template.contains("{name}") && template.contains("{username}") => {
+                return self.username.clone()
+            }
+            None => &self.username,
         };
 
-        if let Some(display_name) = &self.display_name {
-            let full_name = format!("{} (@{}/{})", display_name, source, self.username);
+        let full_name = fill_template(
+            template,
+            &[
+                ("name", name),
+                ("username", &self.username),
+                ("source", source),
+                ("platform", platform),
+            ],
+        );
 
-            if length != 0 && full_name.len() > length {
-                display_name.clone()
-            } else {
-                full_name
-            }
+        if length != 0 && full_name.chars().count() > length {
+            name.to_owned()
         } else {
-            self.username.clone()
+            full_name
         }
     }
 }
@@ -72,6 +97,11 @@ pub struct Message {
     pub id: u64,
     pub in_reply_to: Option<u64>,
     pub reply_author: Option<PartialAuthor>,
+    /// The core ID of the topic or thread the message was sent in, if any.
+    pub topic: Option<u64>,
+    /// What kind of content the message holds. Platforms that can't recreate it natively show
+    /// [`Message::fallback_content`] instead.
+    pub kind: ContentKind,
 }
 
 static NEXT_ID: LazyLock<Mutex<u64>> = LazyLock::new(|| Mutex::new(0));
@@ -95,13 +125,596 @@ impl Message {
             attachments,
             in_reply_to,
             reply_author,
+            topic: None,
+            kind: ContentKind::Text,
+        }
+    }
+
+    /// The content, with a text rendering of anything `content` doesn't already describe.
+    pub fn fallback_content(&self) -> String {
+        match &self.kind {
+            ContentKind::Dice(dice) => format!("{}\n{}", self.content, dice.to_markdown()),
+            _ => self.content.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum ContentKind {
+    /// Plain text, possibly with attachments.
+    #[default]
+    Text,
+    Location(Location),
+    Venue(Venue),
+    /// No platform can send a die with a given value, so receivers show it as text.
+    Dice(Dice),
+    Contact(Contact),
+    Poll(Poll),
+    Sticker(Sticker),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Whether the location is live, and until when. `None` for a static location.
+    pub live: Option<LiveLocation>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LiveLocation {
+    Until(DateTime<Utc>),
+    /// Shared until the user stops it.
+    Indefinite,
+    /// No longer updated, so this is the final position.
+    Ended,
+}
+
+impl Location {
+    pub fn url(self, provider: MapProvider) -> String {
+        provider.url(self.latitude, self.longitude, None)
+    }
+
+    pub fn to_markdown(self, provider: MapProvider) -> String {
+        let url = self.url(provider);
+
+        match self.live {
+            None => format!("Shared location: {url}"),
+            Some(LiveLocation::Until(until)) if until > Utc::now() => format!(
+                "📍 Live location: {url}\n*Live until {} UTC*",
+                until.format("%H:%M")
+            ),
+            Some(LiveLocation::Indefinite) => {
+                format!("📍 Live location: {url}\n*Live until stopped*")
+            }
+            Some(LiveLocation::Until(_) | LiveLocation::Ended) => {
+                format!("📍 Final position: {url}\n*Live location ended*")
+            }
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Venue {
+    pub location: Location,
+    pub title: String,
+    pub address: String,
+    pub google_place_id: Option<String>,
+}
+
+impl Venue {
+    pub fn url(&self, provider: MapProvider) -> String {
+        provider.url(
+            self.location.latitude,
+            self.location.longitude,
+            self.google_place_id.as_deref(),
+        )
+    }
+
+    pub fn to_markdown(&self, provider: MapProvider) -> String {
+        format!(
+            "*Shared a venue*\n**{}**\n{}\n{}",
+            self.title,
+            self.address,
+            self.url(provider)
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dice {
+    pub emoji: String,
+    pub value: u8,
+}
+
+impl Dice {
+    pub fn to_markdown(&self) -> String {
+        match self.emoji.as_str() {
+            "🎲" => match self.value {
+                1 => "⚀".to_string(),
+                2 => "⚁".to_string(),
+                3 => "⚂".to_string(),
+                4 => "⚃".to_string(),
+                5 => "⚄".to_string(),
+                6 => "⚅".to_string(),
+                _ => "🎲".to_string(),
+            },
+            "🎯" => {
+                tracing::debug!("Darts dice value: {}", self.value);
+
+                // 1 is miss
+                // 2 is outer double
+                // 3 is outer single
+                // 4 is triple ring
+                // 5 is inner single
+                // 6 is bullseye
+
+                //  a value of 6 currently represents a bullseye, while a value of 1 indicates that the dartboard was missed.
+                match self.value {
+                    1 => "🎯❌ Miss!",
+                    2 => "🎯🎯 Outer double!",
+                    3 => "🎯🎯 Outer single!",
+                    4 => "🎯🎯🎯 Triple ring!",
+                    5 => "🎯🎯 Inner single!",
+                    6 => "🎯🎯🎯 Bullseye!",
+                    _ => "🎯",
+                }
+                .to_string()
+            }
+            "🎳" => {
+                tracing::debug!("Bowling dice value: {}", self.value);
+
+                // 1 is right gutter
+                // 2 is left taking down only 1 pin
+                // 3 is mid-left, taking down 3 pins
+                // 4 is mid-right, taking down 4 pins
+                // 5 is spare (taking down 5 pins)
+                // 6 is strike
+                // let's do the math
+
+                match self.value {
+                    1 => "🎳❌ Gutter!",
+                    2 => "🎳🫡 1 pin down",
+                    3 => "🎳🫡 Split! 3 pins down",
+                    4 => "🎳🫡 4 pins down",
+                    5 => "🎳🫡 Spare! 5 pins down",
+                    6 => "🎳🎳 Strike!",
+                    _ => "🎳",
+                }
+                .to_string()
+            }
+            "🏀" => {
+                // should be similar to football
+
+                tracing::debug!("Basketball dice value: {}", self.value);
+                let dunk = self.value >= 4;
+                if dunk {
+                    "🏀🔥".to_string()
+                } else {
+                    "🏀❌".to_string()
+                }
+            }
+            "⚽" => {
+                tracing::debug!("Football dice value: {}", self.value);
+                // let's document the mapping here..
+
+                // If emoji is “⚽”, a value of 4 to 5 currently scores a goal, while a value of 1 to 3
+                // indicates that the goal was missed. However, this behaviour is undocumented and might be changed by Telegram.
+
+                // 1 is overshot (miss)
+                // 2 is top left miss
+                // 3 is middle goal?
+                // 4 is left goal, hitting right post and going in net
+                // 5 is top right goal
+
+                let goal = self.value >= 3;
+                if goal {
+                    "⚽️🥅".to_string()
+                } else {
+                    "⚽️❌".to_string()
+                }
+            }
+            "🎰" => {
+                let values: [u8; 3] = [
+                    (self.value - 1) & 3,
+                    ((self.value - 1) >> 2) & 3,
+                    ((self.value - 1) >> 4) & 3,
+                ];
+
+                // map those values to the correct slot emoji
+
+                fn slot(value: u8) -> String {
+                    match value {
+                        // 0 is BAR
+                        0 => "⬛",
+                        1 => "🍇",
+                        2 => "🍋",
+                        3 => "7️⃣",
+                        _ => "🎰",
+                    }
+                    .to_string()
+                }
+
+                let value = format!(
+                    "[{} {} {}]",
+                    slot(values[0]),
+                    slot(values[1]),
+                    slot(values[2])
+                );
+
+                if values.iter().all(|&v| v == 3) {
+                    format!("{value} 🎉🎉🎉 JACKPOT!!! 🎉🎉🎉", value = value)
+                } else {
+                    value
+                }
+            }
+            _ => self.emoji.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Contact {
+    pub phone_number: String,
+    pub first_name: String,
+    pub last_name: Option<String>,
+}
+
+impl Contact {
+    pub fn full_name(&self) -> String {
+        match &self.last_name {
+            Some(last_name) => format!("{} {last_name}", self.first_name),
+            None => self.first_name.clone(),
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        format!(
+            "*Shared a contact*\n**{}**\n{}",
+            self.full_name(),
+            self.phone_number
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sticker {
+    pub name: Option<String>,
+    pub emoji: Option<String>,
+    /// The pack the sticker is from as Markdown, if known.
+    pub pack: Option<String>,
+}
+
+impl Sticker {
+    pub fn to_markdown(&self, animated: bool) -> String {
+        let name = self.emoji.as_deref().or(self.name.as_deref()).unwrap_or("?");
+        let kind = match animated {
+            true => "animated sticker",
+            false => "sticker",
+        };
+
+        match &self.pack {
+            Some(pack) => format!("_{name} {kind} from {pack}_"),
+            None => format!("_{name} {kind}_"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub multiple_answers: bool,
+    /// How long the poll stays open, if it closes on its own.
+    pub duration: Option<Duration>,
+    pub closed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PollOption {
+    pub text: String,
+    /// Number of votes, if the platform shares it.
+    pub votes: Option<u64>,
+}
+
+impl Poll {
+    /// Renders the poll as Markdown, for platforms that can't recreate it natively.
+    pub fn to_markdown(&self) -> String {
+        let options = self
+            .options
+            .iter()
+            .map(|option| format!("- {}", option.text))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let kind = match self.multiple_answers {
+            true => "Poll, multiple answers",
+            false => "Poll",
+        };
+
+        format!("📊 **{}** *({kind})*\n{options}", self.question)
+    }
+
+    /// Renders the poll's vote counts.
+    pub fn results_summary(&self) -> String {
+        let results = self
+            .options
+            .iter()
+            .map(|option| match option.votes {
+                Some(votes) => format!("- {} — **{votes}**", option.text),
+                None => format!("- {} — ?", option.text),
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let state = match self.closed {
+            true => "\n*Poll closed*",
+            false => "",
+        };
+
+        format!("📊 Votes for **{}**\n{results}{state}", self.question)
+    }
+}
+
 #[derive(Debug)]
 pub struct Attachment {
     pub file: TempFile,
+    /// The original filename. May be empty if the platform doesn't have one.
     pub filename: String,
     pub spoilered: bool,
+    pub mime_type: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration: Option<Duration>,
+    /// Alt text or description, if any.
+    pub description: Option<String>,
+    /// Whether this is a voice message rather than a regular audio file.
+    pub voice: bool,
+    /// Loudness samples shown on Discord voice messages, if the source platform had them.
+    pub waveform: Option<Vec<u8>>,
+}
+
+impl Attachment {
+    /// Returns the attachment's filename, falling back to the name of the underlying file.
+    pub fn name(&self) -> String {
+        if self.filename.is_empty() {
+            self.file
+                .file_path()
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            self.filename.clone()
+        }
+    }
+
+    /// Returns the attachment's filename, adding an extension based on its MIME type if it has none.
+    pub async fn name_with_extension(&self) -> Result<String> {
+        let name = self.name();
+        if Path::new(&name).extension().is_some() {
+            return Ok(name);
+        }
+
+        Ok(match self.mime().await?.as_deref().and_then(media::extension_for_mime) {
+            Some(extension) => format!("{name}.{extension}"),
+            None => name,
+        })
+    }
+
+    /// Gets the attachment's MIME type, sniffing it from the file's contents if the platform didn't say.
+    pub async fn mime(&self) -> Result<Option<String>> {
+        if let Some(mime) = &self.mime_type {
+            return Ok(Some(mime.clone()));
+        }
+
+        let mut header = [0; 64];
+        let read = self.file.open_ro().await?.read(&mut header).await?;
+
+        Ok(header
+            .get(..read)
+            .and_then(media::sniff_mime)
+            .or_else(|| media::mime_from_filename(&self.name()))
+            .map(str::to_owned))
+    }
+
+    pub async fn media_type(&self) -> Result<MediaType> {
+        if self.voice {
+            return Ok(MediaType::Voice);
+        }
+
+        Ok(self
+            .mime()
+            .await?
+            .map_or(MediaType::Document, |mime| MediaType::from_mime(&mime)))
+    }
+
+    /// Gets an OGG/Opus copy of the attachment for sending as a voice message.
+    ///
+    /// Returns `None` if the attachment already is OGG/Opus and can be sent as-is.
+    pub async fn opus_file(&self) -> Result<Option<TempFile>> {
+        let mut header = [0; 64];
+        let read = self.file.open_ro().await?.read(&mut header).await?;
+        let header = header.get(..read).unwrap_or_default();
+
+        if header.starts_with(b"OggS") && header.windows(8).any(|w| w == b"OpusHead") {
+            return Ok(None);
+        }
+
+        Ok(Some(media::transcode_to_opus(self.file.file_path()).await?))
+    }
+
+    /// Hashes the attachment's contents, to tell whether an edit changed it.
+    pub async fn hash(&self) -> Result<String> {
+        Ok(sha256::try_async_digest(self.file.file_path()).await?)
+    }
+
+    pub async fn size(&self) -> Result<u64> {
+        Ok(tokio::fs::metadata(self.file.file_path()).await?.len())
+    }
+
+    /// Builds a note to post in place of an attachment that is too large for the receiving platform.
+    ///
+    /// If media storage is configured, the file is uploaded there and the note links to it.
+    pub async fn oversized_note(&self, storage: Option<&Arc<Mutex<R2Storage>>>) -> Result<String> {
+        let size = self.size().await?;
+
+        Ok(match storage {
+            Some(storage) => {
+                let url = storage.lock().await.get_url(&self.file).await?;
+                format!(
+                    "📎 [{}](<{url}>) *({}, too large to upload)*",
+                    self.name(),
+                    format_size(size)
+                )
+            }
+            None => too_large_note(&self.name(), size),
+        })
+    }
+}
+
+/// Splits attachments into the ones that fit under `limit` and notes for the ones that don't.
+pub async fn split_oversized<'a>(
+    attachments: &'a [Attachment],
+    limit: u64,
+    storage: Option<&Arc<Mutex<R2Storage>>>,
+) -> Result<(Vec<&'a Attachment>, Vec<String>)> {
+    let mut fitting = vec![];
+    let mut notes = vec![];
+
+    for attachment in attachments {
+        if attachment.size().await? > limit {
+            notes.push(attachment.oversized_note(storage).await?);
+        } else {
+            fitting.push(attachment);
+        }
+    }
+
+    Ok((fitting, notes))
+}
+
+/// Builds a note for a file that could not be bridged at all.
+pub fn too_large_note(name: &str, size: u64) -> String {
+    format!("📎 {name} *({}, too large to bridge)*", format_size(size))
+}
+
+/// Hashes each attachment, in order.
+pub async fn hash_attachments(attachments: &[Attachment]) -> Result<Vec<String>> {
+    let mut hashes = vec![];
+    for attachment in attachments {
+        hashes.push(attachment.hash().await?);
+    }
+    Ok(hashes)
+}
+
+/// Formats what's left of a deleted message's content.
+pub fn deleted_content(content: &str) -> String {
+    match content.trim().is_empty() {
+        true => "*(deleted)*".to_owned(),
+        false => format!("{} *(deleted)*", strike(content)),
+    }
+}
+
+/// Strikes through every line, since strikethrough doesn't carry over line breaks.
+pub fn strike(text: &str) -> String {
+    text.lines()
+        .map(|line| match line.trim().is_empty() {
+            true => line.to_owned(),
+            false => format!("~~{line}~~"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Fills in the `{placeholders}` of a template, leaving unknown ones as they are.
+pub fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    static PLACEHOLDER_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{(\w+)\}").unwrap());
+
+    PLACEHOLDER_RE
+        .replace_all(template, |captures: &Captures| {
+            values
+                .iter()
+                .find(|(key, _)| *key == &captures[1])
+                .map_or_else(|| captures[0].to_owned(), |(_, value)| (*value).to_owned())
+        })
+        .into_owned()
+}
+
+/// Cuts a string down to at most `length` characters.
+pub fn truncate(text: &str, length: usize) -> String {
+    match text.char_indices().nth(length) {
+        Some((end, _)) => text[..end].to_owned(),
+        None => text.to_owned(),
+    }
+}
+
+/// Escapes the characters Markdown would read as formatting, for names put into content.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']' | '<' | '>' | '#') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn author(display_name: Option<&str>) -> Author {
+        Author {
+            display_name: display_name.map(str::to_owned),
+            username: "user".to_owned(),
+            avatar: None,
+            source: Source::Telegram,
+        }
+    }
+
+    #[test]
+    fn fills_templates() {
+        assert_eq!(
+            fill_template("{a} and {b}, not {c}", &[("a", "one"), ("b", "two")]),
+            "one and two, not {c}"
+        );
+    }
+
+    #[test]
+    fn formats_full_names() {
+        let template = "{name} (@{source}/{username})";
+
+        assert_eq!(author(Some("Name")).full_name(template, 0), "Name (@tg/user)");
+        assert_eq!(author(Some("Name")).full_name(template, 15), "Name (@tg/user)");
+        assert_eq!(author(Some("Name")).full_name(template, 14), "Name");
+        assert_eq!(author(None).full_name(template, 0), "user");
+        assert_eq!(author(None).full_name("{name} [{platform}]", 0), "user [Telegram]");
+        assert_eq!(
+            author(Some("Name")).full_name("{platform}: {name}", 0),
+            "Telegram: Name"
+        );
+    }
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(escape_markdown("__init__ [x]"), r"\_\_init\_\_ \[x\]");
+    }
 }
@@ -1,16 +1,27 @@
+use std::sync::LazyLock;
+
 use crate::{
     broadcast::{BroadcastReceiver, MessageEvent, Source},
-    config::GroupConfig,
-    core::Author,
+    config::{GroupConfig, GroupDiscordConfig, MentionPolicy},
+    core::{self, Author},
+    media,
 };
+use base64::prelude::*;
 use color_eyre::{eyre::eyre, Result};
+use regex::{Captures, Regex};
+use serde_json::json;
 use serenity::{
-    all::{CreateAllowedMentions, CreateAttachment, EditWebhookMessage, ExecuteWebhook, Webhook},
+    all::{
+        ChannelId, ChannelType, CreateAllowedMentions, CreateAttachment, CreateForumPost,
+        CreateMessage, CreateThread, EditThread, EditWebhookMessage, ExecuteWebhook, GuildId, Http,
+        Message, MessageFlags, PremiumTier, RoleId, UserId, Webhook,
+    },
     async_trait,
+    utils::parse_webhook,
 };
 use tracing::*;
 
-use super::DiscordBridge;
+use super::{DiscordBridge, THREAD_NAME_LIMIT};
 
 #[async_trait]
 impl BroadcastReceiver for DiscordBridge {
@@ -33,21 +44,35 @@ impl BroadcastReceiver for DiscordBridge {
                         .await
                         .core_dsc_cache
                         .get(&id)
-                        .map(|result| result.0),
+                        .map(|result| (result.0, result.2)),
                     None => None,
                 };
 
                 // get the message to get the author
                 let reply_msg = match dsc_reply {
-                    Some(id) => Some(
+                    Some((id, thread)) => Some(
                         self.http
                             .clone()
-                            .get_message(dsc.channel.into(), id)
+                            .get_message(thread.unwrap_or(dsc.channel.into()), id)
                             .await?,
                     ),
                     None => None,
                 };
 
+                // replies to thread messages go into that thread
+                let thread = match core_msg.topic {
+                    Some(topic) => self.topic_thread(group, topic).await?,
+                    None => dsc_reply.and_then(|(_, thread)| thread),
+                };
+
+                // replies to Discord messages ping their author, whatever the policy says
+                let reply_user = match &core_msg.reply_author {
+                    Some(author) if author.source == Source::Discord => {
+                        reply_msg.as_ref().map(|msg| msg.author.id)
+                    }
+                    _ => None,
+                };
+
                 // construct either a mention or a plain string
                 let mention = match &core_msg.reply_author {
                     Some(author) => match author.source {
@@ -56,7 +81,10 @@ impl BroadcastReceiver for DiscordBridge {
                             .map(|msg| format!("<@{}>", msg.author.id.get())),
                         _ => {
                             let full_author: Author = author.clone().into();
-                            Some(format!("**{}**", full_author.full_name(Some(0))))
+                            Some(format!(
+                                "**{}**",
+                                full_author.full_name(&group.templates.author, 0)
+                            ))
                         }
                     },
                     None => None,
@@ -64,86 +92,327 @@ impl BroadcastReceiver for DiscordBridge {
 
                 let header = match reply_msg {
                     Some(msg) => {
-                        format!(
-                            "*In reply to {} (https://discord.com/channels/{}/{}/{})*\n",
-                            mention.unwrap_or("???".to_owned()),
+                        let link = format!(
+                            "https://discord.com/channels/{}/{}/{}",
                             msg.guild_id.unwrap_or_default(),
-                            dsc.channel,
+                            msg.channel_id,
                             msg.id.get(),
-                        )
+                        );
+                        let author = mention.unwrap_or("???".to_owned());
+                        // names from Telegram could hold mentions too
+                        let header = core::fill_template(
+                            &group.templates.reply,
+                            &[("author", &author), ("link", &link)],
+                        );
+                        escape_mentions(&header, &dsc.mentions, reply_user) + "\n"
                     }
                     None => String::new(),
                 };
 
-                let builder = ExecuteWebhook::new()
-                    .content(header.clone() + &core_msg.content)
-                    .username(core_msg.author.full_name(None))
-                    .allowed_mentions(CreateAllowedMentions::new().all_users(true));
+                let fallback_content = core_msg.fallback_content();
+                let (files, content) = self
+                    .render(dsc, &webhook, core_msg, &fallback_content)
+                    .await?;
 
-                let builder = match &self.storage {
-                    Some(storage) => match &core_msg.author.avatar {
-                        Some(avatar) => {
-                            builder.avatar_url(storage.lock().await.get_url(avatar).await?)
-                        }
-                        None => builder,
-                    },
-                    None => builder,
+                let username = webhook_name(
+                    &core_msg
+                        .author
+                        .full_name(&group.templates.author, WEBHOOK_NAME_LIMIT),
+                );
+                let avatar_url = match (&self.storage, &core_msg.author.avatar) {
+                    (Some(storage), Some(avatar)) => {
+                        Some(storage.lock().await.get_url(avatar).await?)
+                    }
+                    _ => None,
                 };
 
+                let mut builder = ExecuteWebhook::new()
+                    .content(header.clone() + &content)
+                    .username(&username)
+                    .allowed_mentions(allowed_mentions(&dsc.mentions, reply_user));
+                if let Some(url) = &avatar_url {
+                    builder = builder.avatar_url(url);
+                }
+                if let Some(thread) = thread {
+                    builder = builder.in_thread(thread);
+                }
+
+                // voice messages can't have content or other files, so they're sent on their own
+                let (voice, files): (Vec<_>, Vec<_>) =
+                    files.into_iter().partition(|file| file.voice);
+
                 let mut attachments: Vec<CreateAttachment> = vec![];
-                for file in &core_msg.attachments {
-                    let attachment_old = CreateAttachment::path(&file.file.file_path()).await?;
-                    let attachment = if file.spoilered {
-                        CreateAttachment::bytes(
-                            attachment_old.data,
-                            format!(
-                                "SPOILER_{}",
-                                &file
-                                    .file
-                                    .file_path()
-                                    .file_name()
-                                    .unwrap_or_default()
-                                    .to_string_lossy()
-                            )
-                            .to_owned(),
-                        )
-                    } else {
-                        attachment_old
-                    };
-                    attachments.push(attachment);
+                for file in files {
+                    attachments.push(to_create_attachment(file).await?);
+                }
+
+                let mut messages = vec![];
+                if let core::ContentKind::Poll(poll) = &core_msg.kind {
+                    let payload = poll_payload(
+                        &username,
+                        avatar_url.as_deref(),
+                        &header,
+                        poll,
+                        allowed_mentions(&dsc.mentions, reply_user),
+                    );
+                    match execute_raw(&self.http, &dsc.webhook, thread, vec![], &payload).await {
+                        Ok(msg) => messages.push(msg),
+                        Err(report) => warn!(?report, "Failed to send poll, sending it as text"),
+                    }
                 }
 
-                let builder = builder.add_files(attachments);
-                let msg = webhook.execute(self.http.clone(), true, builder).await?;
+                let poll_sent = !messages.is_empty();
+                if !poll_sent
+                    && (voice.is_empty()
+                        || !(header.is_empty() && content.is_empty() && attachments.is_empty()))
+                {
+                    let builder = builder.add_files(attachments);
+                    messages.extend(webhook.execute(self.http.clone(), true, builder).await?);
+                }
+
+                for file in voice {
+                    let sent = match voice_message(&username, avatar_url.as_deref(), file).await {
+                        Ok((payload, file)) => {
+                            execute_raw(&self.http, &dsc.webhook, thread, vec![file], &payload)
+                                .await
+                        }
+                        Err(report) => Err(report),
+                    };
+                    match sent {
+                        Ok(msg) => messages.push(msg),
+                        Err(report) => {
+                            warn!(
+                                ?report,
+                                "Failed to send voice message, sending it as a file"
+                            );
+                            let mut builder = ExecuteWebhook::new()
+                                .username(&username)
+                                .add_file(to_create_attachment(file).await?);
+                            if let Some(url) = &avatar_url {
+                                builder = builder.avatar_url(url);
+                            }
+                            if let Some(thread) = thread {
+                                builder = builder.in_thread(thread);
+                            }
+                            messages
+                                .extend(webhook.execute(self.http.clone(), true, builder).await?);
+                        }
+                    }
+                }
 
-                if let Some(msg) = msg {
+                let hashes = core::hash_attachments(&core_msg.attachments).await?;
+                if let Some(msg) = messages.first() {
                     let mut cache = self.cache.lock().await;
                     cache
                         .dsc_core_cache
                         .insert(msg.id, (core_msg.id, (&core_msg.author).into()));
-                    cache.core_dsc_cache.insert(core_msg.id, (msg.id, header));
+                    cache
+                        .core_dsc_cache
+                        .insert(core_msg.id, (msg.id, header, thread));
+                    cache
+                        .rendered
+                        .insert(core_msg.id, (vec![fallback_content], hashes));
                 };
             }
 
-            MessageEvent::Update(core_id, text) => {
+            MessageEvent::Update(core_msg) => {
+                // polls can't be edited
+                if let core::ContentKind::Poll(_) = core_msg.kind {
+                    return Ok(());
+                }
+
                 // get dsc message id
-                let (dsc_id, header) = match self.cache.lock().await.core_dsc_cache.get(core_id) {
-                    Some((id, header)) => (*id, header.clone()),
+                let (dsc_id, header, thread, (mut versions, old_hashes)) = {
+                    let cache = self.cache.lock().await;
+                    match (
+                        cache.core_dsc_cache.get(&core_msg.id),
+                        cache.rendered.get(&core_msg.id),
+                    ) {
+                        (Some((id, header, thread)), Some(rendered)) => {
+                            (*id, header.clone(), *thread, rendered.clone())
+                        }
+                        (Some(_), None) => {
+                            debug!("message wasn't sent by the bridge, so it can't be edited");
+                            return Ok(());
+                        }
+                        (None, _) => {
+                            return Err(eyre!(
+                                "could not find core message {} in Discord cache",
+                                core_msg.id
+                            ))
+                        }
+                    }
+                };
+
+                // embeds loading also count as edits on Discord
+                let hashes = core::hash_attachments(&core_msg.attachments).await?;
+                let fallback_content = core_msg.fallback_content();
+                let changed_content = versions.last() != Some(&fallback_content);
+                if !changed_content && hashes == old_hashes {
+                    debug!("edit doesn't change anything, skipping it");
+                    return Ok(());
+                }
+                let edits = group.edits.for_kind(&core_msg.kind);
+                if changed_content {
+                    edits.record(&mut versions, &fallback_content);
+                }
+
+                let (files, content) = self
+                    .render(dsc, &webhook, core_msg, &edits.render(&versions))
+                    .await?;
+
+                let mut builder = EditWebhookMessage::new().content(header + &content);
+                if let Some(thread) = thread {
+                    builder = builder.in_thread(thread);
+                }
+
+                // attachments are only sent again if they changed
+                if hashes != old_hashes {
+                    builder = builder.clear_attachments();
+                    for file in files {
+                        builder = builder.new_attachment(to_create_attachment(file).await?);
+                    }
+                }
+
+                webhook
+                    .edit_message(self.http.clone(), dsc_id, builder)
+                    .await?;
+
+                self.cache
+                    .lock()
+                    .await
+                    .rendered
+                    .insert(core_msg.id, (versions, hashes));
+            }
+
+            MessageEvent::PollUpdate(id, poll) => {
+                let (dsc_id, summary_id) = {
+                    let cache = self.cache.lock().await;
+                    (
+                        cache
+                            .core_dsc_cache
+                            .get(id)
+                            .map(|result| (result.0, result.2)),
+                        cache.poll_summaries.get(id).copied(),
+                    )
+                };
+                let (dsc_id, thread) = match dsc_id {
+                    Some(ids) => ids,
+                    None => return Err(eyre!("could not find core message {id} in Discord cache")),
+                };
+
+                let content = format!(
+                    "*Results (https://discord.com/channels/{}/{}/{})*\n{}",
+                    webhook.guild_id.unwrap_or_default(),
+                    thread.map_or(dsc.channel, ChannelId::get),
+                    dsc_id.get(),
+                    poll.results_summary()
+                );
+
+                match summary_id {
+                    Some(summary_id) => {
+                        let mut builder = EditWebhookMessage::new().content(content);
+                        if let Some(thread) = thread {
+                            builder = builder.in_thread(thread);
+                        }
+                        webhook
+                            .edit_message(self.http.clone(), summary_id, builder)
+                            .await?;
+                    }
                     None => {
-                        return Err(eyre!(
-                            "could not find core message {core_id} in Discord cache"
-                        ))
+                        let mut builder = ExecuteWebhook::new()
+                            .content(content)
+                            .username("Poll results")
+                            .allowed_mentions(CreateAllowedMentions::new());
+                        if let Some(thread) = thread {
+                            builder = builder.in_thread(thread);
+                        }
+                        if let Some(msg) = webhook.execute(self.http.clone(), true, builder).await?
+                        {
+                            self.cache.lock().await.poll_summaries.insert(*id, msg.id);
+                        }
+                    }
+                }
+            }
+
+            MessageEvent::TopicUpdate(topic) => {
+                let (thread, name) = match self.state.lock().await.topic(*topic) {
+                    Some(topic) => (
+                        topic
+                            .discord_thread
+                            .filter(|_| topic.discord_channel == Some(dsc.channel)),
+                        topic.name.clone(),
+                    ),
+                    None => return Err(eyre!("could not find topic {topic}")),
+                };
+
+                match thread {
+                    Some(thread) => {
+                        ChannelId::new(thread)
+                            .edit_thread(
+                                &self.http,
+                                EditThread::new().name(core::truncate(&name, THREAD_NAME_LIMIT)),
+                            )
+                            .await?;
+                    }
+                    None => {
+                        self.topic_thread(group, *topic).await?;
+                    }
+                }
+            }
+
+            MessageEvent::Delete(id) if group.soft_delete => {
+                let (dsc_id, header, thread, versions) = {
+                    let cache = self.cache.lock().await;
+                    match (cache.core_dsc_cache.get(id), cache.rendered.get(id)) {
+                        (Some((dsc_id, header, thread)), Some((versions, _))) => {
+                            (*dsc_id, header.clone(), *thread, versions.clone())
+                        }
+                        _ => {
+                            debug!(
+                                "message wasn't sent by the bridge, so it can't be struck through"
+                            );
+                            return Ok(());
+                        }
                     }
                 };
 
-                let builder = EditWebhookMessage::new().content(header + text);
+                let content = escape_mentions(
+                    &core::deleted_content(versions.last().map_or("", String::as_str)),
+                    &dsc.mentions,
+                    None,
+                );
+                let mut builder = EditWebhookMessage::new().content(header + &content);
+                if let Some(thread) = thread {
+                    builder = builder.in_thread(thread);
+                }
 
                 webhook
                     .edit_message(self.http.clone(), dsc_id, builder)
                     .await?;
             }
 
-            _ => todo!(),
+            MessageEvent::Delete(id) => {
+                let (dsc_id, thread) = match self.cache.lock().await.core_dsc_cache.get(id) {
+                    Some((id, _, thread)) => (*id, *thread),
+                    None => return Err(eyre!("could not find core message {id} in Discord cache")),
+                };
+
+                // messages sent on Discord itself aren't the webhook's, and need Manage Messages
+                if let Err(why) = webhook
+                    .delete_message(self.http.clone(), thread, dsc_id)
+                    .await
+                {
+                    debug!(?why, "not a webhook message, deleting it in the channel");
+                    self.http
+                        .delete_message(
+                            thread.unwrap_or(dsc.channel.into()),
+                            dsc_id,
+                            Some("Deleted on Telegram"),
+                        )
+                        .await?;
+                }
+            }
         };
 
         Ok(())
@@ -153,3 +422,345 @@ impl BroadcastReceiver for DiscordBridge {
         Source::Discord
     }
 }
+
+async fn to_create_attachment(file: &core::Attachment) -> Result<CreateAttachment> {
+    let name = file.name_with_extension().await?;
+    let mut attachment = CreateAttachment::path(&file.file.file_path()).await?;
+    attachment.filename = if file.spoilered {
+        format!("SPOILER_{name}")
+    } else {
+        name
+    };
+    attachment.description = file.description.clone();
+    Ok(attachment)
+}
+
+/// Longest username Discord accepts for webhook messages.
+const WEBHOOK_NAME_LIMIT: usize = 80;
+
+/// Names webhooks can't use, checked case-insensitively anywhere in the name.
+static RESERVED_NAME_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)(d)(iscord)|(c)(lyde)").unwrap());
+
+/// Makes a name acceptable to Discord as the username of a webhook message.
+///
+/// Reserved words get a zero-width space after their first letter, which keeps them readable.
+fn webhook_name(name: &str) -> String {
+    let name = RESERVED_NAME_RE.replace_all(name.trim(), "$1$3\u{200b}$2$4");
+    match core::truncate(&name, WEBHOOK_NAME_LIMIT).trim() {
+        "" => "Unknown".to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+/// Mentions of users, roles, and everyone or here.
+static MENTION_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"<@!?(?P<user>\d+)>|<@&(?P<role>\d+)>|@(?:everyone|here)").unwrap()
+});
+
+/// Breaks up mentions the policy doesn't allow, so they show up as text instead of mentions that
+/// don't ping. The author of the message replied to can always be mentioned.
+fn escape_mentions(content: &str, policy: &MentionPolicy, reply_user: Option<UserId>) -> String {
+    MENTION_RE
+        .replace_all(content, |captures: &Captures| {
+            let id = |name: &str| captures.name(name)?.as_str().parse::<u64>().ok();
+            let allowed = match (id("user"), id("role")) {
+                (Some(user), _) => {
+                    policy.allows_user(user) || reply_user.is_some_and(|id| id.get() == user)
+                }
+                (None, Some(role)) => policy.allows_role(role),
+                (None, None) => policy.everyone,
+            };
+
+            match allowed {
+                true => captures[0].to_owned(),
+                false => captures[0].replacen('@', "@\u{200b}", 1),
+            }
+        })
+        .into_owned()
+}
+
+/// Builds the mentions a message can ping from the policy, plus the author of the message it
+/// replies to.
+fn allowed_mentions(policy: &MentionPolicy, reply_user: Option<UserId>) -> CreateAllowedMentions {
+    let mut mentions = CreateAllowedMentions::new().everyone(policy.everyone);
+
+    // Discord rejects allowing all users or roles alongside a list of them
+    mentions = match (policy.users, policy.allowed_users.is_empty()) {
+        (true, true) => mentions.all_users(true),
+        (true, false) => mentions.users(
+            policy
+                .allowed_users
+                .iter()
+                .map(|&id| UserId::new(id))
+                .chain(reply_user),
+        ),
+        (false, _) => mentions.users(reply_user),
+    };
+    match (policy.roles, policy.allowed_roles.is_empty()) {
+        (true, true) => mentions.all_roles(true),
+        (true, false) => mentions.roles(policy.allowed_roles.iter().map(|&id| RoleId::new(id))),
+        (false, _) => mentions,
+    }
+}
+
+/// Executes a webhook with a payload serenity's builder can't express. This still goes through
+/// serenity's HTTP client, so it's rate limited like every other request.
+async fn execute_raw(
+    http: &Http,
+    webhook_url: &str,
+    thread: Option<ChannelId>,
+    files: Vec<CreateAttachment>,
+    payload: &serde_json::Value,
+) -> Result<Message> {
+    let url = reqwest::Url::parse(webhook_url)?;
+    let (id, token) = parse_webhook(&url).ok_or_else(|| eyre!("invalid webhook URL"))?;
+
+    http.execute_webhook(id, thread, token, true, files, payload)
+        .await?
+        .ok_or_else(|| eyre!("Discord didn't return the sent message"))
+}
+
+/// Builds a voice message for a webhook, with its audio as the only file.
+///
+/// Serenity can't attach the duration and waveform Discord needs for these, so the payload is built by hand.
+#[instrument(skip_all)]
+async fn voice_message(
+    username: &str,
+    avatar_url: Option<&str>,
+    attachment: &core::Attachment,
+) -> Result<(serde_json::Value, CreateAttachment)> {
+    let opus = attachment.opus_file().await?;
+    let path = opus
+        .as_ref()
+        .map_or(attachment.file.file_path(), |opus| opus.file_path());
+
+    let (duration, waveform) = match (attachment.duration, &attachment.waveform) {
+        (Some(duration), Some(waveform)) => (duration, waveform.clone()),
+        (duration, waveform) => {
+            let (measured, sampled) = media::waveform(path).await?;
+            (
+                duration.unwrap_or(measured),
+                waveform.clone().unwrap_or(sampled),
+            )
+        }
+    };
+
+    let payload = json!({
+        "username": username,
+        "avatar_url": avatar_url,
+        "flags": MessageFlags::IS_VOICE_MESSAGE.bits(),
+        "attachments": [{
+            "id": 0,
+            "filename": "voice-message.ogg",
+            "duration_secs": duration.as_secs_f64(),
+            "waveform": BASE64_STANDARD.encode(waveform),
+        }],
+    });
+    let file = CreateAttachment::bytes(tokio::fs::read(path).await?, "voice-message.ogg");
+
+    Ok((payload, file))
+}
+
+/// Builds a native poll for a webhook.
+///
+/// Serenity's webhook builder has no poll field, so the payload is built by hand.
+fn poll_payload(
+    username: &str,
+    avatar_url: Option<&str>,
+    content: &str,
+    poll: &core::Poll,
+    allowed_mentions: CreateAllowedMentions,
+) -> serde_json::Value {
+    // Discord only allows whole hours between 1 hour and 32 days
+    let hours = match poll.duration {
+        Some(duration) => duration.as_secs().div_ceil(3600).clamp(1, 768),
+        None => 24 * 7,
+    };
+
+    let answers: Vec<_> = poll
+        .options
+        .iter()
+        .take(POLL_ANSWER_LIMIT)
+        .map(|option| json!({ "poll_media": { "text": core::truncate(&option.text, 55) } }))
+        .collect();
+
+    json!({
+        "username": username,
+        "avatar_url": avatar_url,
+        "content": content,
+        "allowed_mentions": allowed_mentions,
+        "poll": {
+            "question": { "text": core::truncate(&poll.question, 300) },
+            "answers": answers,
+            "duration": hours,
+            "allow_multiselect": poll.multiple_answers,
+        },
+    })
+}
+
+const POLL_ANSWER_LIMIT: usize = 10;
+
+impl DiscordBridge {
+    /// Splits off attachments too large to upload, and appends notes for those to the given
+    /// content.
+    async fn render<'a>(
+        &self,
+        dsc: &GroupDiscordConfig,
+        webhook: &Webhook,
+        core_msg: &'a core::Message,
+        content: &str,
+    ) -> Result<(Vec<&'a core::Attachment>, String)> {
+        // files over the upload limit get posted as notes instead
+        let (files, notes) = if core_msg.attachments.is_empty() {
+            (vec![], vec![])
+        } else {
+            let limit = match dsc.max_file_size {
+                Some(max) => max.min(self.guild_upload_limit(webhook.guild_id).await),
+                None => self.guild_upload_limit(webhook.guild_id).await,
+            };
+            core::split_oversized(&core_msg.attachments, limit, self.storage.as_ref()).await?
+        };
+
+        let content = [
+            escape_mentions(content, &dsc.mentions, None),
+            notes.join("\n"),
+        ]
+        .join("\n")
+        .trim()
+        .to_owned();
+        Ok((files, content))
+    }
+
+    /// Gets the largest file a guild accepts, based on its boost level.
+    ///
+    /// Guilds that can't be looked up get the limit of unboosted ones.
+    async fn guild_upload_limit(&self, guild_id: Option<GuildId>) -> u64 {
+        let tier = match guild_id {
+            Some(id) => self.premium_tier(id).await,
+            None => PremiumTier::Tier0,
+        };
+
+        match tier {
+            PremiumTier::Tier2 => 50 * 1024 * 1024,
+            PremiumTier::Tier3 => 100 * 1024 * 1024,
+            _ => 10 * 1024 * 1024,
+        }
+    }
+
+    /// Gets a guild's boost level, from the gateway if it knows the guild.
+    async fn premium_tier(&self, id: GuildId) -> PremiumTier {
+        if let Some(guild) = self.gateway_cache.guild(id) {
+            return guild.premium_tier;
+        }
+        if let Some(tier) = self.cache.lock().await.premium_tiers.get(&id) {
+            return *tier;
+        }
+
+        match self.http.get_guild(id).await {
+            Ok(guild) => {
+                self.cache
+                    .lock()
+                    .await
+                    .premium_tiers
+                    .insert(id, guild.premium_tier);
+                guild.premium_tier
+            }
+            Err(why) => {
+                warn!(?why, "could not fetch guild, assuming it has no boosts");
+                PremiumTier::Tier0
+            }
+        }
+    }
+
+    /// Finds the thread bridged to a core topic, creating it if the group bridges topics.
+    ///
+    /// In forum channels, this is a forum post instead.
+    async fn topic_thread(&self, group: &GroupConfig, topic: u64) -> Result<Option<ChannelId>> {
+        let dsc = match &group.discord {
+            Some(dsc) if group.forum_topics => dsc,
+            _ => return Ok(None),
+        };
+
+        let mut state = self.state.lock().await;
+        let topic = match state.topic_mut(topic) {
+            Some(topic) => topic,
+            None => return Err(eyre!("could not find topic {topic}")),
+        };
+
+        if let (Some(thread), Some(channel)) = (topic.discord_thread, topic.discord_channel) {
+            if channel == dsc.channel {
+                return Ok(Some(ChannelId::new(thread)));
+            }
+        }
+
+        let channel = ChannelId::new(dsc.channel);
+        let name = core::truncate(&topic.name, THREAD_NAME_LIMIT);
+        let thread = match channel.to_channel(&self.http).await?.guild() {
+            Some(parent) if parent.kind == ChannelType::Forum => {
+                let starter = CreateMessage::new().content("*Topic bridged from Telegram*");
+                channel
+                    .create_forum_post(&self.http, CreateForumPost::new(name, starter))
+                    .await?
+            }
+            _ => {
+                channel
+                    .create_thread(
+                        &self.http,
+                        CreateThread::new(name).kind(ChannelType::PublicThread),
+                    )
+                    .await?
+            }
+        };
+
+        topic.discord_channel = Some(dsc.channel);
+        topic.discord_thread = Some(thread.id.get());
+        state.save().await?;
+
+        Ok(Some(thread.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_disallowed_mentions() {
+        let policy = MentionPolicy {
+            users: true,
+            allowed_users: vec![1],
+            ..MentionPolicy::default()
+        };
+
+        assert_eq!(
+            escape_mentions("<@1> <@!2> <@&3> @everyone @here", &policy, None),
+            "<@1> <@\u{200b}!2> <@\u{200b}&3> @\u{200b}everyone @\u{200b}here"
+        );
+        assert_eq!(
+            escape_mentions("<@1>", &MentionPolicy::default(), None),
+            "<@\u{200b}1>"
+        );
+        assert_eq!(
+            escape_mentions(
+                "<@1> @everyone",
+                &MentionPolicy::default(),
+                Some(UserId::new(1))
+            ),
+            "<@1> @\u{200b}everyone"
+        );
+    }
+
+    #[test]
+    fn sanitizes_webhook_names() {
+        assert_eq!(
+            webhook_name("Clyde (@tg/discordfan)"),
+            "C\u{200b}lyde (@tg/d\u{200b}iscordfan)"
+        );
+        assert_eq!(webhook_name("   "), "Unknown");
+        assert_eq!(
+            webhook_name(&"a".repeat(100)).chars().count(),
+            WEBHOOK_NAME_LIMIT
+        );
+    }
+}
@@ -1,7 +1,8 @@
 use serenity::{
     all::{
-        ChannelId, Context, EventHandler, GuildId, Message, MessageId, MessageReferenceKind,
-        MessageUpdateEvent,
+        Channel, ChannelId, Context, EventHandler, GuildChannel, GuildId, Http, Message, MessageId,
+        MessagePollVoteAddEvent, MessagePollVoteRemoveEvent, MessageReferenceKind, MessageType,
+        MessageUpdateEvent, UserId,
     },
     async_trait,
 };
@@ -10,40 +11,46 @@ use tracing::*;
 use crate::{
     broadcast::{MessageEvent, Source},
     config::GroupConfig,
+    core::{self, PartialAuthor},
+    state::{NameChat, Topic, NAME_COMMAND},
 };
 
 use super::{
-    parsers::{parse_content, to_core_message},
-    BotEventHandler,
+    parsers::{to_core_message, to_core_poll},
+    BotEventHandler, THREAD_NAME_LIMIT,
 };
 
 #[async_trait]
 impl EventHandler for BotEventHandler {
     #[instrument(skip_all)]
-    async fn message(&self, _ctx: Context, msg: Message) {
-        if msg.author.bot || msg.author.system {
+    async fn message(&self, ctx: Context, msg: Message) {
+        // Discord announces closed polls with a message referencing them
+        if msg.kind == POLL_RESULT {
+            if let Some(id) = msg.message_reference.as_ref().and_then(|r| r.message_id) {
+                self.poll_update(&ctx.http, msg.channel_id, id).await;
+            }
             return;
         }
 
-        // find the respective group
-        let group: Vec<GroupConfig> = self
-            .config
-            .groups
-            .clone()
-            .into_iter()
-            .filter(|g| {
-                g.discord
-                    .as_ref()
-                    .map(|dsc| dsc.channel == msg.channel_id.get())
-                    .unwrap_or(false)
-            })
-            .collect();
+        if msg.author.bot || msg.author.system {
+            return;
+        }
 
-        let group = match group.first() {
-            Some(group) => group,
+        let (group, thread) = match self.find_group(msg.channel_id).await {
+            Some(found) => found,
             None => return,
         };
 
+        if msg.content.split_whitespace().next() == Some(NAME_COMMAND) {
+            self.name_command(&ctx, &msg, group).await;
+            return;
+        }
+
+        let topic = match thread {
+            Some(thread) => self.thread_topic(group, thread).await,
+            None => None,
+        };
+
         // if the message has a reply reference, grab its core ID if possible
         let cached_reply = match &msg.message_reference {
             Some(reference) => match reference.kind {
@@ -58,6 +65,12 @@ impl EventHandler for BotEventHandler {
             None => None,
         };
 
+        // outside of topics, thread messages reply to the thread's first message to keep them together
+        let cached_reply = match (cached_reply, thread, topic) {
+            (None, Some(thread), None) => self.thread_starter(thread, msg.id).await,
+            (cached_reply, _, _) => cached_reply,
+        };
+
         debug!(?cached_reply, "handling message");
 
         // split the Option<tuple> into separate Options
@@ -66,7 +79,17 @@ impl EventHandler for BotEventHandler {
             None => (None, None),
         };
 
-        let core_msg = match to_core_message(&msg, reply_id, reply_author, &self.http).await {
+        let mut core_msg = match to_core_message(
+            &msg,
+            reply_id,
+            reply_author,
+            &ctx,
+            &self.cache,
+            group,
+            &self.config.shared,
+        )
+        .await
+        {
             Ok(core_msg) => core_msg,
             Err(why) => {
                 error!(?why, "Failed to parse into core message");
@@ -74,6 +97,10 @@ impl EventHandler for BotEventHandler {
             }
         };
 
+        core_msg.topic = topic;
+        self.display_name(group, msg.author.id, &mut core_msg.author)
+            .await;
+
         debug!(?core_msg, "got core message");
 
         {
@@ -83,7 +110,7 @@ impl EventHandler for BotEventHandler {
                 .insert(msg.id, (core_msg.id, (&core_msg.author).into()));
             cache
                 .core_dsc_cache
-                .insert(core_msg.id, (msg.id, String::new()));
+                .insert(core_msg.id, (msg.id, String::new(), thread));
         }
 
         debug!("inserted into cache, broadcasting");
@@ -104,7 +131,7 @@ impl EventHandler for BotEventHandler {
         &self,
         ctx: Context,
         _old: Option<Message>,
-        _new: Option<Message>,
+        new: Option<Message>,
         event: MessageUpdateEvent,
     ) {
         if event
@@ -114,22 +141,8 @@ impl EventHandler for BotEventHandler {
             return;
         }
 
-        // find the respective group
-        let group: Vec<GroupConfig> = self
-            .config
-            .groups
-            .clone()
-            .into_iter()
-            .filter(|g| {
-                g.discord
-                    .as_ref()
-                    .map(|dsc| dsc.channel == event.channel_id.get())
-                    .unwrap_or(false)
-            })
-            .collect();
-
-        let group = match group.first() {
-            Some(group) => group,
+        let group = match self.find_group(event.channel_id).await {
+            Some((group, _)) => group,
             None => return,
         };
 
@@ -141,26 +154,103 @@ impl EventHandler for BotEventHandler {
             }
         };
 
-        let content = match parse_content(&event.content.unwrap_or_default(), &ctx.http).await {
-            Ok(content) => content,
-            Err(report) => {
-                error!(?report, "Could not parse content");
+        let pending_edits = self.broadcaster.lock().await.pending_edits();
+        let Some(edit) = pending_edits.settle(core_id).await else {
+            return;
+        };
+
+        // the event only has the fields that changed, so edits are parsed from the full message
+        let msg = match new {
+            Some(msg) => msg,
+            None => match ctx.http.get_message(event.channel_id, event.id).await {
+                Ok(msg) => msg,
+                Err(why) => {
+                    error!(?why, "Could not fetch edited message");
+                    return;
+                }
+            },
+        };
+
+        let mut core_msg = match to_core_message(
+            &msg,
+            None,
+            None,
+            &ctx,
+            &self.cache,
+            group,
+            &self.config.shared,
+        )
+        .await
+        {
+            Ok(core_msg) => core_msg,
+            Err(why) => {
+                error!(?why, "Failed to parse into core message");
                 return;
             }
         };
+        self.display_name(group, msg.author.id, &mut core_msg.author)
+            .await;
+        core_msg.id = core_id;
 
         if let Err(why) = self
             .broadcaster
             .lock()
             .await
-            .broadcast(
-                group,
-                &MessageEvent::Update(core_id, content),
-                Source::Discord,
-            )
+            .broadcast_edit(group, edit, MessageEvent::Update(core_msg), Source::Discord)
             .await
         {
-            error!(?why, "Failed to broadcast message");
+            error!(?why, "Failed to broadcast edit");
+        }
+    }
+
+    async fn poll_vote_add(&self, ctx: Context, event: MessagePollVoteAddEvent) {
+        self.poll_update(&ctx.http, event.channel_id, event.message_id)
+            .await;
+    }
+
+    async fn poll_vote_remove(&self, ctx: Context, event: MessagePollVoteRemoveEvent) {
+        self.poll_update(&ctx.http, event.channel_id, event.message_id)
+            .await;
+    }
+
+    #[instrument(skip_all)]
+    async fn thread_update(&self, _ctx: Context, _old: Option<GuildChannel>, new: GuildChannel) {
+        let topic = {
+            let mut state = self.state.lock().await;
+            let id = match state.discord_topic(new.id.get()) {
+                Some(topic) => topic.id,
+                None => return,
+            };
+            let topic = match state.topic_mut(id) {
+                Some(topic) => topic,
+                None => return,
+            };
+
+            // our own renames come back here too
+            if core::truncate(&topic.name, THREAD_NAME_LIMIT) == new.name {
+                return;
+            }
+            topic.name.clone_from(&new.name);
+
+            if let Err(report) = state.save().await {
+                error!(?report, "Failed to save state");
+            }
+            id
+        };
+
+        let group = match self.find_group(new.id).await {
+            Some((group, _)) => group,
+            None => return,
+        };
+
+        if let Err(why) = self
+            .broadcaster
+            .lock()
+            .await
+            .broadcast(group, &MessageEvent::TopicUpdate(topic), Source::Discord)
+            .await
+        {
+            error!(?why, "Failed to broadcast topic update");
         }
     }
 
@@ -171,38 +261,214 @@ impl EventHandler for BotEventHandler {
         deleted_id: MessageId,
         _guild_id: Option<GuildId>,
     ) {
-        // find the respective group
-        let group: Vec<GroupConfig> = self
-            .config
-            .groups
-            .clone()
-            .into_iter()
-            .filter(|g| {
+        let group = match self.find_group(channel_id).await {
+            Some((group, _)) => group,
+            None => return,
+        };
+
+        let core_id = match self.cache.lock().await.dsc_core_cache.get(&deleted_id) {
+            Some(id) => id.0,
+            None => return,
+        };
+
+        if let Err(why) = self
+            .broadcaster
+            .lock()
+            .await
+            .broadcast(group, &MessageEvent::Delete(core_id), Source::Discord)
+            .await
+        {
+            error!(?why, "Failed to broadcast message");
+        }
+    }
+}
+
+/// Message type of the announcement Discord posts when a poll closes.
+const POLL_RESULT: MessageType = MessageType::Unknown(46);
+
+impl BotEventHandler {
+    /// Finds the group bridging a Discord channel, and the thread if it's one under the bridged channel.
+    async fn find_group(&self, channel_id: ChannelId) -> Option<(&GroupConfig, Option<ChannelId>)> {
+        let find = |channel: ChannelId| {
+            self.config.groups.iter().find(|g| {
                 g.discord
                     .as_ref()
-                    .map(|dsc| dsc.channel == channel_id.get())
-                    .unwrap_or(false)
+                    .is_some_and(|dsc| dsc.channel == channel.get())
             })
-            .collect();
+        };
+
+        if let Some(group) = find(channel_id) {
+            return Some((group, None));
+        }
+
+        let parent = self.thread_parent(channel_id).await?;
+        Some((find(parent)?, Some(channel_id)))
+    }
+
+    /// Gets the channel a thread belongs to, or `None` if the channel isn't a thread.
+    async fn thread_parent(&self, channel_id: ChannelId) -> Option<ChannelId> {
+        if let Some(parent) = self.cache.lock().await.thread_parents.get(&channel_id) {
+            return *parent;
+        }
+
+        let parent = match self.http.get_channel(channel_id).await {
+            Ok(Channel::Guild(channel)) if channel.thread_metadata.is_some() => channel.parent_id,
+            Ok(_) => None,
+            Err(why) => {
+                error!(?why, "Failed to fetch channel");
+                return None;
+            }
+        };
+
+        self.cache
+            .lock()
+            .await
+            .thread_parents
+            .insert(channel_id, parent);
+        parent
+    }
+
+    /// Finds the core topic of a thread, adding it to the state if the group bridges topics.
+    async fn thread_topic(&self, group: &GroupConfig, thread: ChannelId) -> Option<u64> {
+        if let Some(topic) = self.state.lock().await.discord_topic(thread.get()) {
+            return Some(topic.id);
+        }
+
+        if !group.forum_topics {
+            return None;
+        }
+
+        let name = match self.http.get_channel(thread).await {
+            Ok(Channel::Guild(channel)) => channel.name,
+            Ok(_) => return None,
+            Err(why) => {
+                error!(?why, "Failed to fetch thread");
+                return None;
+            }
+        };
+
+        let mut state = self.state.lock().await;
+        let id = state.next_topic_id();
+        state.add_topic(Topic {
+            id,
+            name,
+            telegram_chat: None,
+            telegram_thread: None,
+            discord_channel: group.discord.as_ref().map(|dsc| dsc.channel),
+            discord_thread: Some(thread.get()),
+        });
+        if let Err(report) = state.save().await {
+            error!(?report, "Failed to save state");
+        }
+
+        Some(id)
+    }
+
+    /// Gets the bridged first message of a thread, remembering `message` as it if there's none yet.
+    ///
+    /// Threads started from a message share its ID, so that message is used if it was bridged.
+    async fn thread_starter(
+        &self,
+        thread: ChannelId,
+        message: MessageId,
+    ) -> Option<(u64, PartialAuthor)> {
+        let mut cache = self.cache.lock().await;
 
-        let group = match group.first() {
-            Some(group) => group,
+        let starter = MessageId::new(thread.get());
+        let starter = match cache.dsc_core_cache.contains_key(&starter) {
+            true => starter,
+            false => *cache.thread_starters.entry(thread).or_insert(message),
+        };
+
+        cache.dsc_core_cache.get(&starter).cloned()
+    }
+
+    /// Sets the name the sender shows up as on the other platforms, or resets it if none is given.
+    async fn name_command(&self, ctx: &Context, msg: &Message, group: &GroupConfig) {
+        let channel = match &group.discord {
+            Some(dsc) => dsc.channel,
             None => return,
         };
 
-        let core_id = match self.cache.lock().await.dsc_core_cache.get(&deleted_id) {
+        let reply = {
+            let mut state = self.state.lock().await;
+            let reply = state.name_command(
+                NameChat::Discord(channel),
+                msg.author.id.get(),
+                &msg.content,
+            );
+            if let Err(report) = state.save().await {
+                error!(?report, "Failed to save state");
+                return;
+            }
+            reply
+        };
+
+        if let Err(why) = msg.reply(&ctx.http, reply).await {
+            error!(?why, "Failed to reply to name command");
+        }
+    }
+
+    /// Shows an author under the name they picked with the name command, if any.
+    async fn display_name(&self, group: &GroupConfig, user: UserId, author: &mut core::Author) {
+        let channel = match &group.discord {
+            Some(dsc) => dsc.channel,
+            None => return,
+        };
+        if let Some(name) = self
+            .state
+            .lock()
+            .await
+            .display_name(NameChat::Discord(channel), user.get())
+        {
+            author.display_name = Some(name.to_owned());
+        }
+    }
+
+    /// Broadcasts the current state of a bridged poll once votes stop coming in.
+    #[instrument(skip(self, http))]
+    async fn poll_update(&self, http: &Http, channel_id: ChannelId, message_id: MessageId) {
+        let group = match self.find_group(channel_id).await {
+            Some((group, _)) => group,
+            None => return,
+        };
+
+        let core_id = match self.cache.lock().await.dsc_core_cache.get(&message_id) {
             Some(id) => id.0,
             None => return,
         };
 
+        // votes go through the edit quiet period, so a burst of them costs a single fetch and edit
+        let pending_edits = self.broadcaster.lock().await.pending_edits();
+        let Some(edit) = pending_edits.settle(core_id).await else {
+            return;
+        };
+
+        // vote events don't carry the counts, so get them from the message
+        let poll = match http.get_message(channel_id, message_id).await {
+            Ok(msg) => match msg.poll.as_deref() {
+                Some(poll) => to_core_poll(poll),
+                None => return,
+            },
+            Err(why) => {
+                error!(?why, "Failed to fetch poll message");
+                return;
+            }
+        };
+
         if let Err(why) = self
             .broadcaster
             .lock()
             .await
-            .broadcast(group, &MessageEvent::Delete(core_id), Source::Discord)
+            .broadcast_edit(
+                group,
+                edit,
+                MessageEvent::PollUpdate(core_id, poll),
+                Source::Discord,
+            )
             .await
         {
-            error!(?why, "Failed to broadcast message");
+            error!(?why, "Failed to broadcast poll update");
         }
     }
 }
@@ -1,9 +1,11 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
-use crate::{broadcast::Broadcaster, core::PartialAuthor, storage::R2Storage, Config};
+use crate::{
+    broadcast::Broadcaster, core::PartialAuthor, state::State, storage::R2Storage, Config,
+};
 use color_eyre::Result;
 use serenity::{
-    all::{Http, MessageId},
+    all::{Cache, ChannelId, GuildId, Http, MessageId, PremiumTier, UserId},
     prelude::*,
 };
 use tracing::*;
@@ -13,6 +15,9 @@ mod events;
 mod parsers;
 mod refresh;
 
+/// Longest name Discord allows for threads.
+const THREAD_NAME_LIMIT: usize = 100;
+
 pub struct DiscordBridge {
     storage: Option<Arc<Mutex<R2Storage>>>,
 
@@ -22,7 +27,11 @@ pub struct DiscordBridge {
     /// A copy of the HTTP client for use by other parts of the app.
     http: Arc<Http>,
 
+    /// A copy of what the gateway has told the client about guilds, channels and users.
+    gateway_cache: Arc<Cache>,
+
     cache: Arc<Mutex<DscCache>>,
+    state: Arc<Mutex<State>>,
 }
 
 #[derive(Debug)]
@@ -30,8 +39,28 @@ struct DscCache {
     /// Cache of Discord IDs to (core message IDs, core authors).
     dsc_core_cache: HashMap<MessageId, (u64, PartialAuthor)>,
 
-    /// Cache of core message IDs to (Discord IDs, message headers).
-    core_dsc_cache: HashMap<u64, (MessageId, String)>,
+    /// Cache of core message IDs to (Discord IDs, message headers, threads).
+    core_dsc_cache: HashMap<u64, (MessageId, String, Option<ChannelId>)>,
+
+    /// Cache of core message IDs to the messages summarizing their poll's votes.
+    poll_summaries: HashMap<u64, MessageId>,
+
+    /// Cache of channel IDs to the channels they're threads in, if any.
+    thread_parents: HashMap<ChannelId, Option<ChannelId>>,
+
+    /// Cache of thread IDs to the first bridged message in them.
+    thread_starters: HashMap<ChannelId, MessageId>,
+
+    /// Cache of core message IDs to (every version of their content, attachment hashes), for
+    /// messages sent by the bridge.
+    rendered: HashMap<u64, (Vec<String>, Vec<String>)>,
+
+    /// Cache of (guilds, user IDs) to (names, when they were fetched), for mentioned users serenity's
+    /// cache doesn't have.
+    user_names: HashMap<(Option<GuildId>, UserId), (String, Instant)>,
+
+    /// Cache of guild IDs to their boost levels, for guilds missing from the gateway cache.
+    premium_tiers: HashMap<GuildId, PremiumTier>,
 }
 
 impl DiscordBridge {
@@ -40,6 +69,7 @@ impl DiscordBridge {
         config: Arc<Config>,
         broadcaster: Arc<Mutex<Broadcaster>>,
         storage: Option<Arc<Mutex<R2Storage>>>,
+        state: Arc<Mutex<State>>,
     ) -> Result<Self> {
         debug!("Creating Discord bot");
         let token = match &config.shared.discord_token {
@@ -51,17 +81,28 @@ impl DiscordBridge {
             }
         };
 
-        let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+        let intents = GatewayIntents::GUILD_MESSAGES
+            | GatewayIntents::MESSAGE_CONTENT
+            | GatewayIntents::GUILD_MESSAGE_POLLS
+            | GatewayIntents::GUILDS
+            | GatewayIntents::GUILD_MEMBERS;
 
         let cache = Arc::new(Mutex::new(DscCache {
             dsc_core_cache: HashMap::new(),
             core_dsc_cache: HashMap::new(),
+            poll_summaries: HashMap::new(),
+            thread_parents: HashMap::new(),
+            thread_starters: HashMap::new(),
+            rendered: HashMap::new(),
+            user_names: HashMap::new(),
+            premium_tiers: HashMap::new(),
         }));
 
         let handler = BotEventHandler {
             config: config.clone(),
             broadcaster,
             cache: cache.clone(),
+            state: state.clone(),
             http: Http::new(token),
         };
 
@@ -71,11 +112,18 @@ impl DiscordBridge {
                 .await?,
         ));
 
+        let (http, gateway_cache) = {
+            let client = client.lock().await;
+            (client.http.clone(), client.cache.clone())
+        };
+
         Ok(DiscordBridge {
-            http: client.clone().lock().await.http.clone(),
+            http,
+            gateway_cache,
             client,
             storage,
             cache,
+            state,
         })
     }
 
@@ -94,5 +142,6 @@ struct BotEventHandler {
     config: Arc<Config>,
 
     cache: Arc<Mutex<DscCache>>,
+    state: Arc<Mutex<State>>,
     http: Http,
 }
@@ -1,47 +1,145 @@
-use std::sync::LazyLock;
+use std::{
+    fmt::Write,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
 
-use crate::{broadcast::Source, core::{self, PartialAuthor}, discord::refresh::refresh_cdn_links};
+use crate::{broadcast::Source, config::{GroupConfig, SharedConfig, TimestampConfig}, core::{self, PartialAuthor}, discord::{refresh::refresh_cdn_links, DscCache}};
 use async_tempfile::TempFile;
+use chrono::{DateTime, FixedOffset, Utc};
 use color_eyre::eyre::Result;
-use regex::Regex;
+use regex::{Captures, Regex};
 use serenity::{
-    all::{Attachment, Http, Message, User},
+    all::{
+        AnswerId, Attachment, Channel, ChannelId, Context, Http, Message, MessageFlags,
+        Poll, PollMediaEmoji, Role, RoleId, Timestamp, User, UserId,
+    },
     futures::StreamExt,
+    prelude::Mutex,
 };
+use tracing::*;
 use tokio::io::AsyncWriteExt;
 
-static MENTION_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<@([0-9]+)>").unwrap());
+/// Discord's markup for mentions, custom emoji, timestamps and commands.
+static MARKUP_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"<(?:@!?(?P<user>\d+)|@&(?P<role>\d+)|#(?P<channel>\d+)",
+        r"|(?P<animated>a?):(?P<emoji>\w+):(?P<emoji_id>\d+)",
+        r"|t:(?P<time>-?\d+)(?::(?P<style>[tTdDfFR]))?",
+        r"|/(?P<command>[\w -]+):\d+)>",
+    ))
+    .unwrap()
+});
+/// How long names of mentioned users that had to be fetched are reused for.
+const USER_NAME_TTL: Duration = Duration::from_secs(60 * 60);
+
 static CDN_LINK_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https://(?:cdn\.discordapp\.com|media\.discordapp\.net)/attachments/\d+/\d+/[a-zA-Z0-9.%_\-]+(?:\?[\w\d=&]+)?").unwrap());
 
 pub async fn to_core_message(
     message: &Message,
     in_reply_to: Option<u64>,
     reply_author: Option<PartialAuthor>,
-    http: &Http,
+    ctx: &Context,
+    cache: &Mutex<DscCache>,
+    group: &GroupConfig,
+    shared: &SharedConfig,
 ) -> Result<core::Message> {
-    let dsc_author = &message.author;
-    let core_author = to_core_author(dsc_author)?;
+    // edits fetched over HTTP come without the member, but the cache might have it
+    let nick = match (&message.member, message.guild_id) {
+        (Some(member), _) => member.nick.clone(),
+        (None, Some(guild_id)) => ctx.cache.guild(guild_id).and_then(|guild| {
+            guild
+                .members
+                .get(&message.author.id)
+                .and_then(|member| member.nick.clone())
+        }),
+        (None, None) => None,
+    };
+    let core_author = to_core_author(&message.author, nick)?;
 
     let mut attachments: Vec<core::Attachment> = Vec::new();
 
+    let voice = message
+        .flags
+        .is_some_and(|flags| flags.contains(MessageFlags::IS_VOICE_MESSAGE));
+
     for attachment in &message.attachments {
-        attachments.push(to_core_attachment(attachment).await?);
+        attachments.push(to_core_attachment(attachment, voice).await?);
     }
 
-    let content = parse_content(&message.content, http).await?;
-    Ok(core::Message::new(core_author, content, attachments, in_reply_to, reply_author).await)
+    let kind = match (message.poll.as_deref(), message.sticker_items.first()) {
+        (Some(poll), _) => core::ContentKind::Poll(to_core_poll(poll)),
+        (None, Some(sticker)) => core::ContentKind::Sticker(core::Sticker {
+            name: Some(sticker.name.clone()),
+            emoji: None,
+            pack: None,
+        }),
+        (None, None) => core::ContentKind::Text,
+    };
+
+    let content = match &kind {
+        _ if !message.content.is_empty() => {
+            parse_content(message, ctx, cache, group, shared).await?
+        }
+        core::ContentKind::Poll(poll) => poll.to_markdown(),
+        core::ContentKind::Sticker(sticker) => sticker.to_markdown(false),
+        _ => String::new(),
+    };
+
+    let mut message =
+        core::Message::new(core_author, content, attachments, in_reply_to, reply_author).await;
+    message.kind = kind;
+
+    Ok(message)
 }
 
-pub fn to_core_author(author: &User) -> Result<core::Author> {
+/// Converts a user, shown under their nickname in the guild if they have one.
+pub fn to_core_author(author: &User, nick: Option<String>) -> Result<core::Author> {
     Ok(core::Author {
         username: author.name.to_owned(),
-        display_name: author.global_name.clone(),
+        display_name: nick.or_else(|| author.global_name.clone()),
         avatar: None, // no need to care rn, tg doesn't need it
         source: Source::Discord,
     })
 }
 
-pub async fn to_core_attachment(attachment: &Attachment) -> Result<core::Attachment> {
+pub fn to_core_poll(poll: &Poll) -> core::Poll {
+    let votes = |id: AnswerId| {
+        poll.results.as_ref().map(|results| {
+            results
+                .answer_counts
+                .iter()
+                .find(|count| count.id == id)
+                .map_or(0, |count| count.count)
+        })
+    };
+
+    core::Poll {
+        question: poll.question.text.clone().unwrap_or_default(),
+        options: poll
+            .answers
+            .iter()
+            .map(|answer| core::PollOption {
+                text: match (&answer.poll_media.emoji, &answer.poll_media.text) {
+                    (Some(PollMediaEmoji::Name(emoji)), Some(text)) => format!("{emoji} {text}"),
+                    (_, text) => text.clone().unwrap_or_default(),
+                },
+                votes: votes(answer.answer_id),
+            })
+            .collect(),
+        multiple_answers: poll.allow_multiselect,
+        duration: poll.expiry.and_then(|expiry| {
+            let seconds = expiry.unix_timestamp() - Timestamp::now().unix_timestamp();
+            u64::try_from(seconds).ok().map(Duration::from_secs)
+        }),
+        closed: poll
+            .results
+            .as_ref()
+            .is_some_and(|results| results.is_finalized),
+    }
+}
+
+pub async fn to_core_attachment(attachment: &Attachment, voice: bool) -> Result<core::Attachment> {
     let mut stream = reqwest::get(&attachment.url).await?.bytes_stream();
     let mut file = TempFile::new().await?;
 
@@ -51,29 +149,275 @@ pub async fn to_core_attachment(attachment: &Attachment) -> Result<core::Attachm
 
     file.flush().await?;
 
+    let (filename, spoilered) = match attachment.filename.strip_prefix("SPOILER_") {
+        Some(filename) => (filename.to_owned(), true),
+        None => (attachment.filename.clone(), false),
+    };
+
     Ok(core::Attachment {
         file,
-        spoilered: attachment.filename.starts_with("SPOILER_"),
-        filename: attachment.filename.clone(),
+        filename,
+        spoilered,
+        mime_type: attachment.content_type.clone(),
+        width: attachment.width,
+        height: attachment.height,
+        duration: attachment
+            .duration_secs
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok()),
+        description: attachment.description.clone(),
+        voice,
+        waveform: attachment.waveform.clone(),
     })
 }
 
-pub async fn parse_content(content: &str, http: &Http) -> Result<String> {
-    let mut new_content = content.to_string();
-
-    // find and turn mentions into "@dc/username" format
-    for (_, [id_str]) in MENTION_RE
-        .captures_iter(content)
-        .map(|c| c.extract())
-    {
-        let id = id_str.parse::<u64>()?;
-        let user = http.get_user(id.into()).await;
-        if let Ok(user) = user {
-            new_content = new_content.replace(&format!("<@{id}>"), &format!("@dc/{}", user.name));
+pub async fn parse_content(
+    message: &Message,
+    ctx: &Context,
+    cache: &Mutex<DscCache>,
+    group: &GroupConfig,
+    shared: &SharedConfig,
+) -> Result<String> {
+    let content = &message.content;
+    let mut new_content = String::new();
+    let mut roles: Option<Vec<Role>> = None;
+    let mut last = 0;
+
+    // resolve markup into something readable without Discord
+    for captures in MARKUP_RE.captures_iter(content) {
+        let markup = captures.get(0).expect("captures always have the whole match");
+        new_content.push_str(&content[last..markup.start()]);
+        last = markup.end();
+
+        let resolved = resolve_markup(&captures, message, &mut roles, ctx, cache, group, shared).await;
+        new_content.push_str(resolved.as_deref().unwrap_or(markup.as_str()));
+    }
+    new_content.push_str(&content[last..]);
+
+    get_content_with_refreshed_links(&ctx.http, &new_content).await
+}
+
+/// Resolves a piece of markup, or returns `None` to leave it as it is.
+async fn resolve_markup(
+    captures: &Captures<'_>,
+    message: &Message,
+    roles: &mut Option<Vec<Role>>,
+    ctx: &Context,
+    cache: &Mutex<DscCache>,
+    group: &GroupConfig,
+    shared: &SharedConfig,
+) -> Option<String> {
+    let id = |name: &str| captures.name(name)?.as_str().parse::<u64>().ok();
+    let guild_id = message.guild_id;
+
+    if let Some(id) = id("user") {
+        // turn mentions into "@dc/name" format
+        let name = user_name(UserId::new(id), message, ctx, cache).await?;
+        return Some(format!("@dc/{}", core::escape_markdown(&name)));
+    }
+
+    if let Some(id) = id("channel") {
+        let channel_id = ChannelId::new(id);
+        let cached = guild_id
+            .and_then(|guild_id| ctx.cache.guild(guild_id))
+            .and_then(|guild| {
+                let mut channels = guild.channels.values().chain(&guild.threads);
+                channels
+                    .find(|channel| channel.id == channel_id)
+                    .map(|channel| channel.name.clone())
+            });
+        let name = match cached {
+            Some(name) => name,
+            // channels of other guilds aren't cached
+            None => match ctx.http.get_channel(channel_id).await {
+                Ok(Channel::Guild(channel)) => channel.name,
+                _ => return None,
+            },
+        };
+        let name = core::escape_markdown(&name);
+        return Some(match guild_id {
+            Some(guild_id) => format!("[#{name}](https://discord.com/channels/{guild_id}/{id})"),
+            None => format!("#{name}"),
+        });
+    }
+
+    if let Some(id) = id("role") {
+        let cached = ctx
+            .cache
+            .guild(guild_id?)
+            .map(|guild| guild.roles.get(&RoleId::new(id)).map(|role| role.name.clone()));
+        let name = match cached {
+            Some(name) => name,
+            None => {
+                // roles only come one guild at a time, so they're fetched once per message
+                if roles.is_none() {
+                    *roles = match guild_id?.roles(&ctx.http).await {
+                        Ok(guild_roles) => Some(guild_roles.into_values().collect()),
+                        Err(why) => {
+                            debug!(?why, "could not fetch guild roles");
+                            Some(vec![])
+                        }
+                    };
+                }
+                roles
+                    .as_ref()?
+                    .iter()
+                    .find(|role| role.id.get() == id)
+                    .map(|role| role.name.clone())
+            }
+        };
+        let name = name.unwrap_or_else(|| "deleted-role".to_owned());
+        return Some(format!("@{}", core::escape_markdown(&name)));
+    }
+
+    if let (Some(name), Some(id)) = (captures.name("emoji"), id("emoji_id")) {
+        // Telegram's own Markdown writes custom emoji as images with this URL
+        let mapping = group.discord.as_ref().and_then(|dsc| {
+            dsc.custom_emoji
+                .iter()
+                .find(|mapping| mapping.discord_id() == Some(&id.to_string()))
+        });
+        if let Some(mapping) = mapping {
+            return Some(format!(
+                "![{}](tg://emoji?id={})",
+                mapping.emoji, mapping.telegram
+            ));
+        }
+
+        let extension = match captures.name("animated").is_some_and(|a| !a.is_empty()) {
+            true => "gif",
+            false => "png",
+        };
+        return Some(format!(
+            "[:{}:](https://cdn.discordapp.com/emojis/{id}.{extension})",
+            name.as_str()
+        ));
+    }
+
+    if let Some(time) = captures.name("time") {
+        let time = DateTime::from_timestamp(time.as_str().parse().ok()?, 0)?;
+        let style = captures.name("style").map_or("f", |style| style.as_str());
+        return Some(format_timestamp(time, style, &shared.timestamps, Utc::now()));
+    }
+
+    let command = captures.name("command")?;
+    Some(format!("`/{}`", command.as_str()))
+}
+
+/// Gets the name a user goes by where they were mentioned, preferring their nickname.
+///
+/// The message and serenity's cache usually know it already, so fetching it is the last resort.
+async fn user_name(
+    id: UserId,
+    message: &Message,
+    ctx: &Context,
+    cache: &Mutex<DscCache>,
+) -> Option<String> {
+    if let Some(user) = message.mentions.iter().find(|user| user.id == id) {
+        let nick = user.member.as_ref().and_then(|member| member.nick.clone());
+        return Some(nick.unwrap_or_else(|| user.display_name().to_owned()));
+    }
+
+    let cached = match message.guild_id {
+        Some(guild_id) => ctx.cache.guild(guild_id).and_then(|guild| {
+            guild
+                .members
+                .get(&id)
+                .map(|member| member.display_name().to_owned())
+        }),
+        None => ctx.cache.user(id).map(|user| user.display_name().to_owned()),
+    };
+    if cached.is_some() {
+        return cached;
+    }
+
+    let key = (message.guild_id, id);
+    if let Some((name, fetched)) = cache.lock().await.user_names.get(&key) {
+        if fetched.elapsed() < USER_NAME_TTL {
+            return Some(name.clone());
+        }
+    }
+
+    let name = match message.guild_id {
+        Some(guild_id) => match guild_id.member(ctx, id).await {
+            Ok(member) => member.display_name().to_owned(),
+            Err(why) => {
+                debug!(?why, "could not fetch mentioned member");
+                ctx.http.get_user(id).await.ok()?.display_name().to_owned()
+            }
+        },
+        None => ctx.http.get_user(id).await.ok()?.display_name().to_owned(),
+    };
+    let mut cache = cache.lock().await;
+    // expired names are only dropped here, so the cache doesn't grow for the life of the process
+    cache
+        .user_names
+        .retain(|_, (_, fetched)| fetched.elapsed() < USER_NAME_TTL);
+    cache.user_names.insert(key, (name.clone(), Instant::now()));
+
+    Some(name)
+}
+
+/// Writes out a timestamp in one of Discord's styles.
+fn format_timestamp(
+    time: DateTime<Utc>,
+    style: &str,
+    config: &TimestampConfig,
+    now: DateTime<Utc>,
+) -> String {
+    if style == "R" {
+        return relative_time(time - now);
+    }
+
+    let offset = match config.utc_offset.as_deref().map(str::parse::<FixedOffset>) {
+        Some(Ok(offset)) => offset,
+        Some(Err(why)) => {
+            warn!(?why, "invalid UTC offset for timestamps, using UTC");
+            FixedOffset::east_opt(0).expect("zero is a valid offset")
+        }
+        None => FixedOffset::east_opt(0).expect("zero is a valid offset"),
+    };
+    let local = time.with_timezone(&offset);
+
+    // invalid formats only fail once written out, and `to_string` would panic on that
+    if let Some(format) = &config.format {
+        let mut formatted = String::new();
+        match write!(formatted, "{}", local.format(format)) {
+            Ok(()) => return formatted,
+            Err(_) => warn!("invalid timestamp format, using the default"),
         }
     }
 
-    get_content_with_refreshed_links(http, &new_content).await
+    let zone = match offset.local_minus_utc() {
+        0 => "UTC".to_owned(),
+        _ => offset.to_string(),
+    };
+    match style {
+        "t" => format!("{} {zone}", local.format("%H:%M")),
+        "T" => format!("{} {zone}", local.format("%H:%M:%S")),
+        "d" => local.format("%d/%m/%Y").to_string(),
+        "D" => local.format("%-d %B %Y").to_string(),
+        "F" => format!("{} {zone}", local.format("%A, %-d %B %Y %H:%M")),
+        _ => format!("{} {zone}", local.format("%-d %B %Y %H:%M")),
+    }
+}
+
+/// Writes out how far away a point in time is, like "in 3 hours" or "2 days ago".
+fn relative_time(delta: chrono::TimeDelta) -> String {
+    let seconds = delta.num_seconds().unsigned_abs();
+    let (amount, unit) = match seconds {
+        0..60 => return "just now".to_owned(),
+        60..3600 => (seconds / 60, "minute"),
+        3600..86400 => (seconds / 3600, "hour"),
+        86400..2_592_000 => (seconds / 86400, "day"),
+        2_592_000..31_536_000 => (seconds / 2_592_000, "month"),
+        _ => (seconds / 31_536_000, "year"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+
+    match delta.num_seconds() < 0 {
+        true => format!("{amount} {unit}{plural} ago"),
+        false => format!("in {amount} {unit}{plural}"),
+    }
 }
 
 async fn get_content_with_refreshed_links(http: &Http, content: &str) -> Result<String> {
@@ -87,3 +431,28 @@ async fn get_content_with_refreshed_links(http: &Http, content: &str) -> Result<
 
     Ok(content)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps_in_styles() {
+        let time = DateTime::from_timestamp(1_618_935_600, 0).unwrap();
+        let config = TimestampConfig {
+            utc_offset: Some("+02:00".to_owned()),
+            format: None,
+        };
+
+        assert_eq!(format_timestamp(time, "t", &config, time), "18:20 +02:00");
+        assert_eq!(format_timestamp(time, "D", &config, time), "20 April 2021");
+        assert_eq!(
+            format_timestamp(time, "F", &TimestampConfig::default(), time),
+            "Tuesday, 20 April 2021 16:20 UTC"
+        );
+        assert_eq!(
+            format_timestamp(time, "R", &config, time - chrono::TimeDelta::hours(3)),
+            "in 3 hours"
+        );
+    }
+}
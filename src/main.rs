@@ -10,6 +10,8 @@ mod broadcast;
 mod config;
 mod core;
 mod discord;
+mod media;
+mod state;
 mod storage;
 mod telegram;
 pub use config::Config;
@@ -49,14 +51,26 @@ async fn main() -> Result<()> {
         None
     };
 
+    let state = Arc::new(Mutex::new(
+        state::State::load(config.shared.state_file.as_ref()).await?,
+    ));
+
     let broadcaster = Arc::new(Mutex::new(Broadcaster::init()));
 
     let telegram = Arc::new(telegram::TelegramBridge::init(
         broadcaster.clone(),
         config.clone(),
+        storage.clone(),
+        state.clone(),
     ));
     let discord = Arc::new(
-        discord::DiscordBridge::new(config.clone(), broadcaster.clone(), storage.clone()).await?,
+        discord::DiscordBridge::new(
+            config.clone(),
+            broadcaster.clone(),
+            storage.clone(),
+            state.clone(),
+        )
+        .await?,
     );
 
     {
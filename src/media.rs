@@ -0,0 +1,207 @@
+use std::{ffi::OsStr, path::Path, process::Stdio, time::Duration};
+
+use async_tempfile::TempFile;
+use color_eyre::{eyre::eyre, Result};
+use tokio::process::Command;
+use tracing::*;
+
+/// The kind of media an attachment holds, as far as the platforms care.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Photo,
+    Video,
+    Animation,
+    Audio,
+    /// A voice message. Never guessed from a MIME type, only set by the source platform.
+    Voice,
+    Document,
+}
+
+impl MediaType {
+    pub fn from_mime(mime: &str) -> Self {
+        // drop parameters like `; codecs=opus`
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+
+        match mime {
+            "image/png" | "image/jpeg" | "image/webp" => Self::Photo,
+            "image/gif" => Self::Animation,
+            _ if mime.starts_with("video/") => Self::Video,
+            _ if mime.starts_with("audio/") => Self::Audio,
+            _ => Self::Document,
+        }
+    }
+}
+
+/// Guesses a MIME type from the first bytes of a file.
+pub fn sniff_mime(bytes: &[u8]) -> Option<&'static str> {
+    let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("audio/wav"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'M', b'4', b'A', ..] => Some("audio/mp4"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'q', b't', ..] => Some("video/quicktime"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("video/mp4"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] if contains(b"webm") => Some("video/webm"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some("video/x-matroska"),
+        [b'O', b'g', b'g', b'S', ..] if contains(b"theora") => Some("video/ogg"),
+        [b'O', b'g', b'g', b'S', ..] => Some("audio/ogg"),
+        [b'f', b'L', b'a', b'C', ..] => Some("audio/flac"),
+        [b'I', b'D', b'3', ..] => Some("audio/mpeg"),
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 => Some("audio/mpeg"),
+        [b'%', b'P', b'D', b'F', ..] => Some("application/pdf"),
+        [0x1F, 0x8B, ..] => Some("application/gzip"),
+        [b'P', b'K', 0x03, 0x04, ..] => Some("application/zip"),
+        _ => None,
+    }
+}
+
+/// Guesses a MIME type from a filename's extension.
+pub fn mime_from_filename(filename: &str) -> Option<&'static str> {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(OsStr::to_str)?
+        .to_lowercase();
+
+    Some(match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "flac" => "audio/flac",
+        "pdf" => "application/pdf",
+        "tgs" => "application/x-tgsticker",
+        _ => return None,
+    })
+}
+
+/// Gets the usual file extension for a MIME type.
+pub fn extension_for_mime(mime: &str) -> Option<&'static str> {
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+
+    Some(match mime {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        "video/mp4" => "mp4",
+        "video/quicktime" => "mov",
+        "video/x-matroska" => "mkv",
+        "video/webm" => "webm",
+        "audio/mpeg" => "mp3",
+        "audio/mp4" => "m4a",
+        "audio/wav" => "wav",
+        "audio/ogg" => "ogg",
+        "audio/flac" => "flac",
+        "application/pdf" => "pdf",
+        _ => return None,
+    })
+}
+
+/// Transcodes an audio file to OGG/Opus, the format both platforms want voice messages in.
+#[instrument]
+pub async fn transcode_to_opus(input: &Path) -> Result<TempFile> {
+    let output = TempFile::new().await?;
+
+    let status = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(input)
+        .args(["-vn", "-c:a", "libopus", "-b:a", "48k", "-f", "ogg"])
+        .arg(output.file_path())
+        .stdin(Stdio::null())
+        .status()
+        .await?;
+
+    if !status.success() {
+        return Err(eyre!("ffmpeg exited with {status}"));
+    }
+
+    Ok(output)
+}
+
+/// Samples the loudness of an audio file into a waveform like the one Discord shows on voice
+/// messages, and measures how long it is.
+///
+/// Each byte is the peak of a 100 ms window, scaled to 0-255, with at most 256 windows.
+#[instrument]
+pub async fn waveform(input: &Path) -> Result<(Duration, Vec<u8>)> {
+    let output = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-i"])
+        .arg(input)
+        .args(["-ac", "1", "-ar"])
+        .arg(WAVEFORM_SAMPLE_RATE.to_string())
+        .args(["-f", "s16le", "-"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(eyre!("ffmpeg exited with {}", output.status));
+    }
+
+    let samples: Vec<u16> = output
+        .stdout
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]).unsigned_abs())
+        .collect();
+
+    let duration =
+        Duration::from_millis(samples.len() as u64 * 1000 / u64::from(WAVEFORM_SAMPLE_RATE));
+    let windows = usize::try_from(duration.as_millis() / 100)
+        .unwrap_or(usize::MAX)
+        .clamp(1, 256);
+    let window_size = samples.len().div_ceil(windows).max(1);
+
+    let waveform = samples
+        .chunks(window_size)
+        .map(|window| {
+            let peak = u32::from(window.iter().copied().max().unwrap_or_default());
+            u8::try_from(peak * 255 / 32768).unwrap_or(u8::MAX)
+        })
+        .collect();
+
+    Ok((duration, waveform))
+}
+
+/// Samples per second of the audio a waveform is sampled from.
+const WAVEFORM_SAMPLE_RATE: u32 = 8000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_common_formats() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n\0\0"), Some("image/png"));
+        assert_eq!(
+            sniff_mime(b"\xff\xd8\xff\xe0\0\x10JFIF"),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff_mime(b"\0\0\0\x20ftypisom\0\0"), Some("video/mp4"));
+        assert_eq!(sniff_mime(b"OggS\0\x02\0\0OpusHead"), Some("audio/ogg"));
+        assert_eq!(sniff_mime(b"hello, world!"), None);
+    }
+
+    #[test]
+    fn picks_media_type_from_mime() {
+        assert_eq!(MediaType::from_mime("image/jpeg"), MediaType::Photo);
+        assert_eq!(MediaType::from_mime("image/gif"), MediaType::Animation);
+        assert_eq!(
+            MediaType::from_mime("audio/ogg; codecs=opus"),
+            MediaType::Audio
+        );
+        assert_eq!(MediaType::from_mime("image/svg+xml"), MediaType::Document);
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+/// Bridge state that has to survive restarts, kept in a JSON file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(skip)]
+    path: PathBuf,
+
+    #[serde(default)]
+    topics: Vec<Topic>,
+
+    #[serde(default)]
+    display_names: Vec<DisplayName>,
+}
+
+/// A Telegram forum topic or Discord thread, and its counterpart on the other platform.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Topic {
+    /// The core topic ID, carried by core messages.
+    pub id: u64,
+    pub name: String,
+    pub telegram_chat: Option<i64>,
+    pub telegram_thread: Option<i32>,
+    /// The bridged channel the thread lives in.
+    pub discord_channel: Option<u64>,
+    pub discord_thread: Option<u64>,
+}
+
+/// Command that sets the name the sender shows up as on the other platforms.
+pub const NAME_COMMAND: &str = "/name";
+
+/// Longest name that can be picked with the name command, in characters.
+const DISPLAY_NAME_LIMIT: usize = 64;
+
+/// A name someone picked to be shown as on the other platforms, instead of their own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisplayName {
+    pub chat: NameChat,
+    pub user: u64,
+    pub name: String,
+}
+
+/// The chat a display name was picked in, which decides the platform of its user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameChat {
+    Telegram(i64),
+    Discord(u64),
+}
+
+impl State {
+    /// Reads the state file, starting from scratch if it doesn't exist yet.
+    #[instrument]
+    pub async fn load(path: &Path) -> Result<Self> {
+        let mut state: Self = match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no state file yet, starting fresh");
+                Self::default()
+            }
+            Err(why) => return Err(why.into()),
+        };
+
+        state.path = path.to_owned();
+        Ok(state)
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        // written next to the state file and moved over it, so a failed write can't truncate it
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        tokio::fs::write(&temp, serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::rename(&temp, &self.path).await?;
+        Ok(())
+    }
+
+    pub fn topic(&self, id: u64) -> Option<&Topic> {
+        self.topics.iter().find(|topic| topic.id == id)
+    }
+
+    pub fn topic_mut(&mut self, id: u64) -> Option<&mut Topic> {
+        self.topics.iter_mut().find(|topic| topic.id == id)
+    }
+
+    pub fn telegram_topic(&self, chat: i64, thread: i32) -> Option<&Topic> {
+        self.topics.iter().find(|topic| {
+            topic.telegram_chat == Some(chat) && topic.telegram_thread == Some(thread)
+        })
+    }
+
+    pub fn discord_topic(&self, thread: u64) -> Option<&Topic> {
+        self.topics
+            .iter()
+            .find(|topic| topic.discord_thread == Some(thread))
+    }
+
+    /// Gets an ID no topic uses yet.
+    pub fn next_topic_id(&self) -> u64 {
+        self.topics
+            .iter()
+            .map(|topic| topic.id + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn add_topic(&mut self, topic: Topic) {
+        self.topics.push(topic);
+    }
+
+    pub fn display_name(&self, chat: NameChat, user: u64) -> Option<&str> {
+        self.display_names
+            .iter()
+            .find(|name| name.chat == chat && name.user == user)
+            .map(|name| name.name.as_str())
+    }
+
+    /// Replaces someone's display name, or removes it if the new one is empty.
+    pub fn set_display_name(&mut self, display_name: DisplayName) {
+        self.display_names
+            .retain(|name| name.chat != display_name.chat || name.user != display_name.user);
+        if !display_name.name.is_empty() {
+            self.display_names.push(display_name);
+        }
+    }
+
+    /// Sets the name the sender of a name command shows up as on the other platforms, or resets
+    /// it if none is given. Returns the reply for the sender.
+    pub fn name_command(&mut self, chat: NameChat, user: u64, text: &str) -> String {
+        let name = text
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, name)| name.trim());
+
+        if name.chars().any(char::is_control) {
+            return "Names have to fit on a single line.".to_owned();
+        }
+        if name.chars().count() > DISPLAY_NAME_LIMIT {
+            return format!("Names can be at most {DISPLAY_NAME_LIMIT} characters long.");
+        }
+
+        self.set_display_name(DisplayName {
+            chat,
+            user,
+            name: name.to_owned(),
+        });
+        match name {
+            "" => "You'll show up under your own name again.".to_owned(),
+            name => format!("You'll show up as {name} on the other side."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_and_resets_display_names() {
+        let mut state = State::default();
+        let chat = NameChat::Telegram(1);
+        let other_chat = NameChat::Discord(1);
+
+        state.name_command(chat, 2, "/name first");
+        state.name_command(other_chat, 2, "/name elsewhere");
+        state.name_command(chat, 2, "/name  second ");
+        assert_eq!(state.display_name(chat, 2), Some("second"));
+        assert_eq!(state.display_name(other_chat, 2), Some("elsewhere"));
+
+        state.name_command(chat, 2, "/name two\nlines");
+        state.name_command(chat, 2, &format!("/name {}", "a".repeat(65)));
+        assert_eq!(state.display_name(chat, 2), Some("second"));
+
+        state.name_command(chat, 2, "/name");
+        assert_eq!(state.display_name(chat, 2), None);
+        assert_eq!(state.display_name(other_chat, 2), Some("elsewhere"));
+    }
+}
@@ -1,22 +1,32 @@
-use std::{ffi::OsStr, path::Path};
-
 use crate::{
     broadcast::{BroadcastReceiver, MessageEvent, Source},
     config::GroupConfig,
+    core,
+    media::MediaType,
 };
 use color_eyre::eyre::{eyre, Result};
 use serenity::async_trait;
 use teloxide::{
-    payloads::{EditMessageTextSetters, SendMediaGroupSetters, SendMessageSetters},
+    payloads::{
+        EditForumTopicSetters, EditMessageCaptionSetters, EditMessageTextSetters,
+        SendAnimationSetters, SendAudioSetters, SendContactSetters, SendDocumentSetters,
+        SendLocationSetters, SendMediaGroupSetters, SendMessageSetters, SendPhotoSetters,
+        SendPollSetters, SendVenueSetters, SendVideoSetters, SendVoiceSetters,
+    },
     prelude::Requester,
+    requests::HasPayload,
     types::{
         ChatId, InputFile, InputMedia, InputMediaAudio, InputMediaDocument, InputMediaPhoto,
-        InputMediaVideo, Recipient, ReplyParameters,
+        InputMediaVideo, Message, MessageEntity, MessageId, Recipient, ReplyParameters, ThreadId,
     },
+    Bot,
 };
 use tracing::*;
 
-use super::{entities::to_string_with_entities, TelegramBridge};
+use super::{
+    entities::to_string_with_entities, Rendered, SentAs, TelegramBridge, LOCAL_UPLOAD_LIMIT,
+    UPLOAD_LIMIT,
+};
 
 #[async_trait]
 impl BroadcastReceiver for TelegramBridge {
@@ -30,14 +40,14 @@ impl BroadcastReceiver for TelegramBridge {
 
         match event {
             MessageEvent::Create(core_msg) => {
-                let text = format!(
-                    "**{}**\n{}",
-                    &core_msg.author.full_name(Some(0)),
-                    &core_msg.content
-                );
-
-                let parsed = to_string_with_entities(&text);
-                let content = String::from_utf16_lossy(&parsed.0);
+                let fallback_content = core_msg.fallback_content();
+                let (attachments, content, entities) =
+                    self.render(group, core_msg, &fallback_content).await?;
+                let mut rendered = Rendered {
+                    sent_as: SentAs::Text,
+                    versions: vec![fallback_content],
+                    attachment_hashes: core::hash_attachments(&core_msg.attachments).await?,
+                };
 
                 // get core ID of reply if possible
                 let tg_reply = match core_msg.in_reply_to {
@@ -51,109 +61,328 @@ impl BroadcastReceiver for TelegramBridge {
                     None => None,
                 };
 
-                let messages = if !core_msg.attachments.is_empty() {
-                    let media = core_msg
-                        .attachments
-                        .iter()
-                        .enumerate()
-                        .map(|(i, attachment)| {
-                            let file = InputFile::file(attachment.file.file_path())
-                                .file_name(attachment.filename.clone());
-
-                            match Path::new(&attachment.filename)
-                                .extension()
-                                .and_then(OsStr::to_str)
-                            {
-                                Some("png") | Some("jpg") | Some("jpeg") | Some("webp") => {
-                                    let media = InputMediaPhoto::new(file);
-                                    let media = match i {
-                                        0 => media
-                                            .caption(&content)
-                                            .caption_entities(parsed.1.clone()),
-                                        _ => media,
-                                    };
-                                    InputMedia::Photo(media)
-                                }
-                                Some("mp4") | Some("mov") | Some("mkv") | Some("webm") => {
-                                    let media = InputMediaVideo::new(file);
-                                    let media = match i {
-                                        0 => media
-                                            .caption(&content)
-                                            .caption_entities(parsed.1.clone()),
-                                        _ => media,
-                                    };
-                                    InputMedia::Video(media)
-                                }
-                                Some("mp3") | Some("wav") | Some("ogg") | Some("flac") => {
-                                    let media = InputMediaAudio::new(file);
-                                    let media = match i {
-                                        0 => media
-                                            .caption(&content)
-                                            .caption_entities(parsed.1.clone()),
-                                        _ => media,
-                                    };
-                                    InputMedia::Audio(media)
-                                }
-                                _ => {
-                                    let media = InputMediaDocument::new(file);
-                                    let media = match i {
-                                        0 => media
-                                            .caption(&content)
-                                            .caption_entities(parsed.1.clone()),
-                                        _ => media,
-                                    };
-                                    InputMedia::Document(media)
-                                }
-                            }
-                        })
-                        .collect::<Vec<InputMedia>>();
-                    self.bot
-                        .send_media_group(chat_id, media)
-                        .reply_parameters(match tg_reply {
-                            Some(id) => ReplyParameters::new(id),
-                            None => ReplyParameters::default(),
-                        })
-                        .await?
-                } else {
-                    vec![
-                        self.bot
-                            .send_message(chat_id, content)
-                            .entities(parsed.1)
+                let thread = match core_msg.topic {
+                    Some(topic) => self.topic_thread(group, topic).await?,
+                    None => None,
+                };
+
+                let (messages, sent_as) = match &core_msg.kind {
+                    core::ContentKind::Poll(poll) => {
+                        // polls can't carry text or files, so the message's own go before it
+                        let mut messages = vec![];
+                        let mut reply = tg_reply;
+                        if core_msg.content != poll.to_markdown() || !attachments.is_empty() {
+                            (messages, _) = self
+                                .send_rendered(
+                                    chat_id.clone(),
+                                    &attachments,
+                                    &content,
+                                    entities,
+                                    tg_reply,
+                                    thread,
+                                )
+                                .await?;
+                            reply = messages.first().map(|msg| msg.id);
+                        }
+
+                        let question = format!(
+                            "{}: {}",
+                            core_msg.author.full_name(&group.templates.author, 0),
+                            poll.question
+                        );
+                        let options = poll
+                            .options
+                            .iter()
+                            .take(POLL_OPTION_LIMIT)
+                            .map(|option| core::truncate(&option.text, 100));
+
+                        let mut request = self
+                            .bot
+                            .send_poll(chat_id, core::truncate(&question, 300), options)
+                            .allows_multiple_answers(poll.multiple_answers)
+                            .reply_parameters(match reply {
+                                Some(id) => ReplyParameters::new(id),
+                                None => ReplyParameters::default(),
+                            })
+                            .with_payload_mut(|payload| payload.message_thread_id = thread);
+
+                        // polls that run longer than Telegram allows get closed by a poll update instead
+                        if let Some(period) = poll
+                            .duration
+                            .and_then(|duration| u16::try_from(duration.as_secs()).ok())
+                            .filter(|period| (5..=600).contains(period))
+                        {
+                            request = request.open_period(period);
+                        }
+
+                        let msg = request.await?;
+                        if let (Some(sent_poll), Some(chat)) = (msg.poll(), group.telegram_chat) {
+                            self.cache
+                                .lock()
+                                .await
+                                .poll_core_cache
+                                .insert(sent_poll.id.clone(), (ChatId(chat), core_msg.id));
+                        }
+                        // poll updates go to the first message, so that's the poll
+                        messages.insert(0, msg);
+                        (messages, SentAs::Native)
+                    }
+
+                    kind @ (core::ContentKind::Location(_)
+                    | core::ContentKind::Venue(_)
+                    | core::ContentKind::Contact(_)) => {
+                        // these can't carry a caption, so the author goes in a message before them
+                        let header = to_string_with_entities(&core::fill_template(
+                            &group.templates.header,
+                            &[(
+                                "author",
+                                &core_msg.author.full_name(&group.templates.author, 0),
+                            )],
+                        ));
+                        let header_msg = self
+                            .bot
+                            .send_message(chat_id.clone(), String::from_utf16_lossy(&header.0))
+                            .entities(header.1)
                             .reply_parameters(match tg_reply {
                                 Some(id) => ReplyParameters::new(id),
                                 None => ReplyParameters::default(),
                             })
-                            .await?,
-                    ]
+                            .with_payload_mut(|payload| payload.message_thread_id = thread)
+                            .await?;
+                        let native =
+                            send_native(&self.bot, chat_id, kind, header_msg.id, thread).await?;
+                        (vec![header_msg, native], SentAs::Native)
+                    }
+
+                    _ => {
+                        self.send_rendered(
+                            chat_id,
+                            &attachments,
+                            &content,
+                            entities,
+                            tg_reply,
+                            thread,
+                        )
+                        .await?
+                    }
                 };
 
                 if let Some(msg) = messages.first() {
                     let mut cache = self.cache.lock().await;
                     cache.core_tg_cache.insert(
                         core_msg.id,
-                        (msg.id, core_msg.author.full_name(Some(0)).clone()),
+                        (
+                            msg.id,
+                            core_msg.author.full_name(&group.templates.author, 0),
+                        ),
                     );
                     cache
                         .tg_core_cache
                         .insert(msg.id, (core_msg.id, (&core_msg.author).into()));
+                    rendered.sent_as = sent_as;
+                    cache.rendered.insert(core_msg.id, rendered);
                 };
             }
 
-            MessageEvent::Update(id, content) => {
-                // get telegram ID and author name
-                let (tg_id, author) = match self.cache.lock().await.core_tg_cache.get(id) {
-                    Some((id, str)) => (*id, str.clone()),
+            MessageEvent::Update(core_msg) => {
+                let tg_id = match self.cache.lock().await.core_tg_cache.get(&core_msg.id) {
+                    Some((id, _)) => *id,
+                    None => {
+                        return Err(eyre!(
+                            "could not find core message {} on Telegram",
+                            core_msg.id
+                        ))
+                    }
+                };
+
+                let old = match self.cache.lock().await.rendered.get(&core_msg.id) {
+                    Some(rendered) => rendered.clone(),
+                    None => {
+                        debug!("message wasn't sent by the bridge, so it can't be edited");
+                        return Ok(());
+                    }
+                };
+
+                // polls can't be edited, and native locations, venues and contacts are
+                // preceded by a header that only holds the author
+                if old.sent_as == SentAs::Native {
+                    return Ok(());
+                }
+
+                let mut rendered = Rendered {
+                    sent_as: old.sent_as,
+                    versions: old.versions.clone(),
+                    attachment_hashes: core::hash_attachments(&core_msg.attachments).await?,
+                };
+
+                // Discord also sends edits for embeds loading, which Telegram rejects as unmodified
+                let fallback_content = core_msg.fallback_content();
+                let changed_content = old.versions.last() != Some(&fallback_content);
+                let changed_attachments = rendered.attachment_hashes != old.attachment_hashes;
+                if !changed_content && !changed_attachments {
+                    debug!("edit doesn't change anything, skipping it");
+                    return Ok(());
+                }
+                let edits = group.edits.for_kind(&core_msg.kind);
+                if changed_content {
+                    edits.record(&mut rendered.versions, &fallback_content);
+                }
+
+                let (attachments, content, entities) = self
+                    .render(group, core_msg, &edits.render(&rendered.versions))
+                    .await?;
+
+                let media = matches!(old.sent_as, SentAs::Media | SentAs::MediaGroup);
+                match attachments.first() {
+                    Some(first) if media && changed_attachments => {
+                        if old.sent_as == SentAs::MediaGroup {
+                            debug!("only replacing the first attachment of a media group");
+                        }
+                        let input = to_input_media(first, Some((&content, entities))).await?;
+                        self.bot.edit_message_media(chat_id, tg_id, input).await?;
+                    }
+                    _ if media => {
+                        if changed_attachments {
+                            debug!("media can't be removed from a Telegram message, only editing its caption");
+                        }
+                        self.bot
+                            .edit_message_caption(chat_id, tg_id)
+                            .caption(content)
+                            .caption_entities(entities)
+                            .await?;
+                    }
+                    _ => {
+                        self.bot
+                            .edit_message_text(chat_id.clone(), tg_id, content)
+                            .entities(entities)
+                            .await?;
+
+                        // text messages can't gain media, so new attachments go in a reply
+                        if !attachments.is_empty() && changed_attachments {
+                            let thread = match core_msg.topic {
+                                Some(topic) => self.topic_thread(group, topic).await?,
+                                None => None,
+                            };
+                            send_media(&self.bot, chat_id, &attachments, None, Some(tg_id), thread)
+                                .await?;
+                        }
+                    }
+                }
+
+                self.cache
+                    .lock()
+                    .await
+                    .rendered
+                    .insert(core_msg.id, rendered);
+            }
+
+            MessageEvent::PollUpdate(id, poll) => {
+                let (tg_id, summary_id) = {
+                    let cache = self.cache.lock().await;
+                    (
+                        cache.core_tg_cache.get(id).map(|result| result.0),
+                        cache.poll_summaries.get(id).copied(),
+                    )
+                };
+                let tg_id = match tg_id {
+                    Some(id) => id,
                     None => return Err(eyre!("could not find core message {id} on Telegram")),
                 };
 
-                let text = format!("**{}**\n{}", &author, &content);
+                let parsed = to_string_with_entities(&poll.results_summary());
+                let text = String::from_utf16_lossy(&parsed.0);
+
+                match summary_id {
+                    Some(summary_id) => {
+                        // fails if nothing changed, which is fine
+                        if let Err(why) = self
+                            .bot
+                            .edit_message_text(chat_id.clone(), summary_id, text)
+                            .entities(parsed.1)
+                            .await
+                        {
+                            debug!(?why, "could not edit poll summary");
+                        }
+                    }
+                    None => {
+                        let msg = self
+                            .bot
+                            .send_message(chat_id.clone(), text)
+                            .entities(parsed.1)
+                            .reply_parameters(ReplyParameters::new(tg_id))
+                            .await?;
+                        self.cache.lock().await.poll_summaries.insert(*id, msg.id);
+                    }
+                }
+
+                if poll.closed {
+                    // only works for polls the bot sent itself
+                    if let Err(why) = self.bot.stop_poll(chat_id, tg_id).await {
+                        debug!(?why, "could not stop poll");
+                    }
+                }
+            }
+
+            MessageEvent::TopicUpdate(topic) => {
+                let (thread, name) = match self.state.lock().await.topic(*topic) {
+                    Some(topic) => (
+                        topic
+                            .telegram_thread
+                            .filter(|_| topic.telegram_chat == group.telegram_chat),
+                        topic.name.clone(),
+                    ),
+                    None => return Err(eyre!("could not find topic {topic}")),
+                };
 
+                match thread {
+                    Some(thread) => {
+                        self.bot
+                            .edit_forum_topic(chat_id, ThreadId(MessageId(thread)))
+                            .name(core::truncate(&name, TOPIC_NAME_LIMIT))
+                            .await?;
+                    }
+                    None => {
+                        self.topic_thread(group, *topic).await?;
+                    }
+                }
+            }
+
+            MessageEvent::Delete(id) if group.soft_delete => {
+                let (tg_id, author, rendered) = {
+                    let cache = self.cache.lock().await;
+                    match (cache.core_tg_cache.get(id), cache.rendered.get(id)) {
+                        (Some((tg_id, author)), Some(rendered)) => {
+                            (*tg_id, author.clone(), rendered.clone())
+                        }
+                        _ => {
+                            debug!(
+                                "message wasn't sent by the bridge, so it can't be struck through"
+                            );
+                            return Ok(());
+                        }
+                    }
+                };
+
+                let text = format!(
+                    "{}\n{}",
+                    core::fill_template(&group.templates.header, &[("author", &author)]),
+                    core::deleted_content(rendered.versions.last().map_or("", String::as_str))
+                );
                 let parsed = to_string_with_entities(&text);
+                let content = String::from_utf16_lossy(&parsed.0);
 
-                self.bot
-                    .edit_message_text(chat_id, tg_id, String::from_utf16_lossy(&parsed.0))
-                    .entities(parsed.1)
-                    .await?;
+                if matches!(rendered.sent_as, SentAs::Media | SentAs::MediaGroup) {
+                    self.bot
+                        .edit_message_caption(chat_id, tg_id)
+                        .caption(content)
+                        .caption_entities(parsed.1)
+                        .await?;
+                } else {
+                    self.bot
+                        .edit_message_text(chat_id, tg_id, content)
+                        .entities(parsed.1)
+                        .await?;
+                }
             }
 
             // this might not work correctly with multi-attachment messages
@@ -174,3 +403,437 @@ impl BroadcastReceiver for TelegramBridge {
         Source::Telegram
     }
 }
+
+impl TelegramBridge {
+    /// Splits off attachments too large to upload, and formats the given content with the
+    /// message's author and notes for those attachments.
+    async fn render<'a>(
+        &self,
+        group: &GroupConfig,
+        core_msg: &'a core::Message,
+        content: &str,
+    ) -> Result<(Vec<&'a core::Attachment>, String, Vec<MessageEntity>)> {
+        // files over the upload limit get posted as notes instead
+        let upload_limit = if self.config.shared.telegram_local_mode {
+            LOCAL_UPLOAD_LIMIT
+        } else {
+            UPLOAD_LIMIT
+        };
+        let limit = group
+            .telegram_max_file_size
+            .map_or(upload_limit, |max| max.min(upload_limit));
+        let (attachments, notes) =
+            core::split_oversized(&core_msg.attachments, limit, self.storage.as_ref()).await?;
+
+        let header = core::fill_template(
+            &group.templates.header,
+            &[(
+                "author",
+                &core_msg.author.full_name(&group.templates.author, 0),
+            )],
+        );
+        let text = format!(
+            "{header}\n{}",
+            [content.to_owned(), notes.join("\n")].join("\n").trim()
+        );
+
+        let parsed = to_string_with_entities(&text);
+        Ok((attachments, String::from_utf16_lossy(&parsed.0), parsed.1))
+    }
+
+    /// Sends rendered content as a text message, or as the caption of its attachments.
+    async fn send_rendered(
+        &self,
+        chat_id: Recipient,
+        attachments: &[&core::Attachment],
+        content: &str,
+        entities: Vec<MessageEntity>,
+        reply: Option<MessageId>,
+        thread: Option<ThreadId>,
+    ) -> Result<(Vec<Message>, SentAs)> {
+        if attachments.is_empty() {
+            let msg = self
+                .bot
+                .send_message(chat_id, content)
+                .entities(entities)
+                .reply_parameters(match reply {
+                    Some(id) => ReplyParameters::new(id),
+                    None => ReplyParameters::default(),
+                })
+                .with_payload_mut(|payload| payload.message_thread_id = thread)
+                .await?;
+            return Ok((vec![msg], SentAs::Text));
+        }
+
+        let mut types = vec![];
+        for attachment in attachments {
+            types.push(attachment.media_type().await?);
+        }
+
+        let groups = partition_media(&types);
+        let sent_as = match groups.first().map(Vec::len) {
+            Some(1) => SentAs::Media,
+            _ => SentAs::MediaGroup,
+        };
+
+        let mut messages: Vec<Message> = vec![];
+        for group in groups {
+            let group: Vec<_> = group
+                .iter()
+                .filter_map(|&i| attachments.get(i).copied())
+                .collect();
+
+            // only the first message gets the caption and the reply
+            let (caption, reply) = match messages.is_empty() {
+                true => (Some((content, entities.clone())), reply),
+                false => (None, None),
+            };
+
+            messages.extend(
+                send_media(&self.bot, chat_id.clone(), &group, caption, reply, thread).await?,
+            );
+        }
+        Ok((messages, sent_as))
+    }
+
+    /// Finds the forum topic bridged to a core topic, creating it if the group bridges topics.
+    async fn topic_thread(&self, group: &GroupConfig, topic: u64) -> Result<Option<ThreadId>> {
+        let chat = match group.telegram_chat {
+            Some(chat) if group.forum_topics => chat,
+            _ => return Ok(None),
+        };
+
+        let mut state = self.state.lock().await;
+        let topic = match state.topic_mut(topic) {
+            Some(topic) => topic,
+            None => return Err(eyre!("could not find topic {topic}")),
+        };
+
+        if let (Some(thread), Some(topic_chat)) = (topic.telegram_thread, topic.telegram_chat) {
+            if topic_chat == chat {
+                return Ok(Some(ThreadId(MessageId(thread))));
+            }
+        }
+
+        let created = self
+            .bot
+            .create_forum_topic(
+                ChatId(chat),
+                core::truncate(&topic.name, TOPIC_NAME_LIMIT),
+                TOPIC_ICON_COLOR,
+                "",
+            )
+            .await?;
+        topic.telegram_chat = Some(chat);
+        topic.telegram_thread = Some(created.thread_id.0 .0);
+        state.save().await?;
+
+        Ok(Some(created.thread_id))
+    }
+}
+
+const TOPIC_NAME_LIMIT: usize = 128;
+
+/// Light blue, one of the few colors Telegram allows for topic icons.
+const TOPIC_ICON_COLOR: u32 = 0x6F_B9F0;
+
+/// Builds the media group item for an attachment, picking the media type from its metadata.
+async fn to_input_media(
+    attachment: &core::Attachment,
+    caption: Option<(&str, Vec<MessageEntity>)>,
+) -> Result<InputMedia> {
+    let file = InputFile::file(attachment.file.file_path())
+        .file_name(attachment.name_with_extension().await?);
+    let width = attachment.width.and_then(|x| u16::try_from(x).ok());
+    let height = attachment.height.and_then(|x| u16::try_from(x).ok());
+    let duration = attachment
+        .duration
+        .and_then(|x| u16::try_from(x.as_secs()).ok());
+
+    Ok(match attachment.media_type().await? {
+        MediaType::Photo => {
+            let mut media = InputMediaPhoto::new(file);
+            if let Some((text, entities)) = caption {
+                media = media.caption(text).caption_entities(entities);
+            }
+            media.has_spoiler = attachment.spoilered;
+            InputMedia::Photo(media)
+        }
+        MediaType::Video => {
+            let mut media = InputMediaVideo::new(file);
+            if let Some((text, entities)) = caption {
+                media = media.caption(text).caption_entities(entities);
+            }
+            media.has_spoiler = attachment.spoilered;
+            media.width = width;
+            media.height = height;
+            media.duration = duration;
+            InputMedia::Video(media)
+        }
+        MediaType::Audio | MediaType::Voice => {
+            let mut media = InputMediaAudio::new(file);
+            if let Some((text, entities)) = caption {
+                media = media.caption(text).caption_entities(entities);
+            }
+            media.duration = duration;
+            InputMedia::Audio(media)
+        }
+        // `partition_media` never groups animations, but documents are the closest fit
+        MediaType::Animation | MediaType::Document => {
+            let mut media = InputMediaDocument::new(file);
+            if let Some((text, entities)) = caption {
+                media = media.caption(text).caption_entities(entities);
+            }
+            InputMedia::Document(media)
+        }
+    })
+}
+
+/// Splits attachments into groups that Telegram accepts in one `sendMediaGroup` call, keeping their order.
+///
+/// Photos and videos can be mixed, audio and documents can only be grouped with their own kind,
+/// animations and voice messages can't be grouped at all, and a group holds at most 10 items.
+fn partition_media(types: &[MediaType]) -> Vec<Vec<usize>> {
+    let class = |media_type: &MediaType| match media_type {
+        MediaType::Photo | MediaType::Video => Some(0),
+        MediaType::Audio => Some(1),
+        MediaType::Document => Some(2),
+        MediaType::Animation | MediaType::Voice => None,
+    };
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for (i, media_type) in types.iter().enumerate() {
+        let fits = match (groups.last(), class(media_type)) {
+            (Some(group), Some(class_)) => {
+                group.len() < MEDIA_GROUP_LIMIT
+                    && group
+                        .first()
+                        .and_then(|&first| types.get(first))
+                        .and_then(class)
+                        == Some(class_)
+            }
+            _ => false,
+        };
+
+        match groups.last_mut() {
+            Some(group) if fits => group.push(i),
+            _ => groups.push(vec![i]),
+        }
+    }
+
+    groups
+}
+
+const MEDIA_GROUP_LIMIT: usize = 10;
+
+const POLL_OPTION_LIMIT: usize = 10;
+
+/// Sends a location, venue or contact as Telegram's own message type.
+async fn send_native(
+    bot: &Bot,
+    chat_id: Recipient,
+    kind: &core::ContentKind,
+    reply: MessageId,
+    thread: Option<ThreadId>,
+) -> Result<Message> {
+    let reply = ReplyParameters::new(reply);
+
+    Ok(match kind {
+        core::ContentKind::Location(location) => {
+            bot.send_location(chat_id, location.latitude, location.longitude)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread)
+                .await?
+        }
+        core::ContentKind::Venue(venue) => {
+            let mut request = bot
+                .send_venue(
+                    chat_id,
+                    venue.location.latitude,
+                    venue.location.longitude,
+                    &venue.title,
+                    &venue.address,
+                )
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread);
+            if let Some(id) = &venue.google_place_id {
+                request = request.google_place_id(id);
+            }
+            request.await?
+        }
+        core::ContentKind::Contact(contact) => {
+            let mut request = bot
+                .send_contact(chat_id, &contact.phone_number, &contact.first_name)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread);
+            if let Some(last_name) = &contact.last_name {
+                request = request.last_name(last_name);
+            }
+            request.await?
+        }
+        kind => return Err(eyre!("{kind:?} can't be sent natively")),
+    })
+}
+
+/// Sends a group of attachments, using the dedicated method for the media type if there's only one.
+async fn send_media(
+    bot: &Bot,
+    chat_id: Recipient,
+    attachments: &[&core::Attachment],
+    caption: Option<(&str, Vec<MessageEntity>)>,
+    reply: Option<MessageId>,
+    thread: Option<ThreadId>,
+) -> Result<Vec<Message>> {
+    let reply = match reply {
+        Some(id) => ReplyParameters::new(id),
+        None => ReplyParameters::default(),
+    };
+
+    let attachment = match attachments {
+        [attachment] => attachment,
+        _ => {
+            let mut media = vec![];
+            for (i, attachment) in attachments.iter().enumerate() {
+                let caption = match i {
+                    0 => caption.clone(),
+                    _ => None,
+                };
+                media.push(to_input_media(attachment, caption).await?);
+            }
+
+            return Ok(bot
+                .send_media_group(chat_id, media)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread)
+                .await?);
+        }
+    };
+
+    let file = InputFile::file(attachment.file.file_path())
+        .file_name(attachment.name_with_extension().await?);
+    let (text, entities) = caption.unwrap_or_default();
+    let duration = attachment
+        .duration
+        .and_then(|x| u32::try_from(x.as_secs()).ok());
+
+    let message = match attachment.media_type().await? {
+        MediaType::Photo => {
+            bot.send_photo(chat_id, file)
+                .caption(text)
+                .caption_entities(entities)
+                .has_spoiler(attachment.spoilered)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread)
+                .await?
+        }
+        MediaType::Video => {
+            let mut request = bot
+                .send_video(chat_id, file)
+                .caption(text)
+                .caption_entities(entities)
+                .has_spoiler(attachment.spoilered)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread);
+            if let (Some(width), Some(height)) = (attachment.width, attachment.height) {
+                request = request.width(width).height(height);
+            }
+            if let Some(duration) = duration {
+                request = request.duration(duration);
+            }
+            request.await?
+        }
+        MediaType::Animation => {
+            let mut request = bot
+                .send_animation(chat_id, file)
+                .caption(text)
+                .caption_entities(entities)
+                .has_spoiler(attachment.spoilered)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread);
+            if let (Some(width), Some(height)) = (attachment.width, attachment.height) {
+                request = request.width(width).height(height);
+            }
+            if let Some(duration) = duration {
+                request = request.duration(duration);
+            }
+            request.await?
+        }
+        MediaType::Audio => {
+            let mut request = bot
+                .send_audio(chat_id, file)
+                .caption(text)
+                .caption_entities(entities)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread);
+            if let Some(duration) = duration {
+                request = request.duration(duration);
+            }
+            request.await?
+        }
+        MediaType::Voice => {
+            // Telegram only shows OGG/Opus files as voice messages
+            let opus = attachment.opus_file().await?;
+            let file = match &opus {
+                Some(opus) => InputFile::file(opus.file_path()).file_name("voice.ogg"),
+                None => file,
+            };
+
+            let mut request = bot
+                .send_voice(chat_id, file)
+                .caption(text)
+                .caption_entities(entities)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread);
+            if let Some(duration) = duration {
+                request = request.duration(duration);
+            }
+            request.await?
+        }
+        MediaType::Document => {
+            bot.send_document(chat_id, file)
+                .caption(text)
+                .caption_entities(entities)
+                .reply_parameters(reply)
+                .with_payload_mut(|payload| payload.message_thread_id = thread)
+                .await?
+        }
+    };
+
+    Ok(vec![message])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixes_photos_and_videos() {
+        let types = [MediaType::Photo, MediaType::Video, MediaType::Photo];
+        assert_eq!(partition_media(&types), vec![vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn separates_audio_documents_and_animations() {
+        let types = [
+            MediaType::Photo,
+            MediaType::Audio,
+            MediaType::Audio,
+            MediaType::Document,
+            MediaType::Animation,
+            MediaType::Voice,
+        ];
+        assert_eq!(
+            partition_media(&types),
+            vec![vec![0], vec![1, 2], vec![3], vec![4], vec![5]]
+        );
+    }
+
+    #[test]
+    fn splits_groups_over_ten_items() {
+        let types = [MediaType::Photo; 12];
+        assert_eq!(
+            partition_media(&types),
+            vec![(0..10).collect::<Vec<_>>(), vec![10, 11]]
+        );
+    }
+}
@@ -1,10 +1,13 @@
+use std::{cmp::Reverse, sync::LazyLock};
+
+use color_eyre::{eyre::eyre, Result};
 use markdown::{
     mdast::{self, Node},
-    ParseOptions,
+    Constructs, ParseOptions,
 };
-use regex::Regex;
+use regex::{Captures, Regex};
 use teloxide::types::{MessageEntity, MessageEntityKind};
-use tracing::debug;
+use tracing::warn;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct StringWithEntities(pub Vec<u16>, pub Vec<MessageEntity>);
@@ -52,17 +55,71 @@ impl From<&str> for StringWithEntities {
     }
 }
 
-fn nodes_to_entities(nodes: Vec<Node>) -> StringWithEntities {
-    StringWithEntities::join_strings(nodes.iter().map(node_to_entities).collect())
+/// What rendering a node needs to know about the whole document.
+struct Context<'a> {
+    source: &'a str,
+}
+
+impl<'a> Context<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { source }
+    }
+
+    /// The Markdown a node was parsed from.
+    fn source_of(&self, node: &Node) -> String {
+        node.position()
+            .and_then(|position| self.source.get(position.start.offset..position.end.offset))
+            .map_or_else(|| node.to_string(), str::to_owned)
+    }
+}
+
+/// Marks the whole string with an entity, before the ones it already has.
+fn with_entity(string: StringWithEntities, kind: MessageEntityKind) -> StringWithEntities {
+    let entity = MessageEntity {
+        kind,
+        offset: 0,
+        length: string.0.len(),
+    };
+    let entities = [entity].into_iter().chain(string.1).collect();
+
+    StringWithEntities(string.0, entities)
+}
+
+/// Links the string if the URL is valid, leaving it as-is otherwise.
+fn with_link(string: StringWithEntities, url: &str) -> StringWithEntities {
+    match reqwest::Url::parse(url) {
+        Ok(url) => with_entity(string, MessageEntityKind::TextLink { url }),
+        Err(_) => string,
+    }
+}
+
+fn nodes_to_entities(nodes: &[Node], ctx: &Context) -> StringWithEntities {
+    StringWithEntities::join_strings(
+        nodes
+            .iter()
+            .map(|node| node_to_entities(node, ctx))
+            .collect(),
+    )
 }
 
-fn node_to_entities(node: &Node) -> StringWithEntities {
+fn node_to_entities(node: &Node, ctx: &Context) -> StringWithEntities {
     match node {
         Node::Root(mdast::Root { children, .. })
-        | Node::ListItem(mdast::ListItem { children, .. }) => nodes_to_entities(children.clone()),
+        | Node::TableRow(mdast::TableRow { children, .. })
+        | Node::TableCell(mdast::TableCell { children, .. }) => nodes_to_entities(children, ctx),
+
+        Node::ListItem(item) => {
+            let mut string: StringWithEntities = match item.checked {
+                Some(true) => "☑ ".into(),
+                Some(false) => "☐ ".into(),
+                None => StringWithEntities::new(),
+            };
+            string.join(&nodes_to_entities(&item.children, ctx));
+            string
+        }
 
         Node::Paragraph(mdast::Paragraph { children, .. }) => {
-            let mut string = nodes_to_entities(children.clone());
+            let mut string = nodes_to_entities(children, ctx);
             string.0.push('\n' as u16);
             string
         }
@@ -70,51 +127,60 @@ fn node_to_entities(node: &Node) -> StringWithEntities {
         Node::Text(text) => text.value.clone().into(),
 
         Node::Strong(strong) => {
-            let string = nodes_to_entities(strong.children.clone());
-
-            let entity = MessageEntity::bold(0, string.0.len());
-            let entities = [entity].into_iter().chain(string.1).collect();
-
-            StringWithEntities(string.0.clone(), entities)
+            let string = nodes_to_entities(&strong.children, ctx);
+
+            // `__text__` is underline on Discord, where most Markdown here comes from
+            let underline = ctx.source_of(node).starts_with("__");
+            match underline {
+                true => with_entity(string, MessageEntityKind::Underline),
+                false => with_entity(string, MessageEntityKind::Bold),
+            }
         }
 
-        Node::Emphasis(em) => {
-            let string = nodes_to_entities(em.children.clone());
+        Node::Emphasis(em) => with_entity(
+            nodes_to_entities(&em.children, ctx),
+            MessageEntityKind::Italic,
+        ),
+
+        Node::Delete(delete) => with_entity(
+            nodes_to_entities(&delete.children, ctx),
+            MessageEntityKind::Strikethrough,
+        ),
 
-            let entity = MessageEntity::italic(0, string.0.len());
-            let entities = [entity].into_iter().chain(string.1).collect();
+        Node::InlineCode(node) => with_entity(node.value.clone().into(), MessageEntityKind::Code),
 
-            StringWithEntities(string.0.clone(), entities)
+        Node::Code(node) => {
+            let mut string = with_entity(
+                node.value.clone().into(),
+                MessageEntityKind::Pre {
+                    language: node.lang.clone(),
+                },
+            );
+            string.0.push('\n' as u16);
+            string
         }
 
-        Node::InlineCode(node) => StringWithEntities(
-            node.value.encode_utf16().collect(),
-            vec![MessageEntity::code(0, node.value.len())],
-        ),
-
-        Node::Code(node) => StringWithEntities(
-            node.value.encode_utf16().collect(),
-            vec![MessageEntity::pre(node.lang.clone(), 0, node.value.len())],
-        ),
+        // Discord only has three levels of headings
+        Node::Heading(heading) if heading.depth > 3 => {
+            let mut string: StringWithEntities = ctx.source_of(node).into();
+            string.0.push('\n' as u16);
+            string
+        }
 
         Node::Heading(heading) => {
-            let string = nodes_to_entities(heading.children.clone());
-            let full_heading = ("#".repeat(heading.depth.into()) + " ")
-                .encode_utf16()
-                .chain(string.0)
-                .collect::<Vec<u16>>();
-
-            let entity = MessageEntity::bold(0, full_heading.len());
-            let entities = [entity].into_iter().chain(string.1).collect();
+            let mut string: StringWithEntities = ("#".repeat(heading.depth.into()) + " ").into();
+            string.join(&nodes_to_entities(&heading.children, ctx));
 
-            StringWithEntities(full_heading, entities)
+            let mut string = with_entity(string, MessageEntityKind::Bold);
+            string.0.push('\n' as u16);
+            string
         }
 
         Node::List(list) => {
             let children = list
                 .children
                 .iter()
-                .map(node_to_entities)
+                .map(|child| node_to_entities(child, ctx))
                 .collect::<Vec<StringWithEntities>>();
 
             // if unordered, prepend "• " to the children
@@ -139,59 +205,247 @@ fn node_to_entities(node: &Node) -> StringWithEntities {
             StringWithEntities::join_strings(children)
         }
 
-        Node::Link(link) => {
-            let string = nodes_to_entities(link.children.clone());
+        // relative links and the like have nowhere to go, so they're left as text
+        Node::Link(link) => with_link(nodes_to_entities(&link.children, ctx), &link.url),
 
-            let entity = match link.url.as_str() {
-                "x-oxibridge:spoiler" => MessageEntity::spoiler(
-                    0,
-                    string.0.len(),
-                ),
-                _ => MessageEntity::text_link(
-                    reqwest::Url::parse(&link.url).expect("Failed to parse link's URL"),
-                    0,
-                    string.0.len(),
-                ),
-            };
+        // how Telegram's own Markdown writes custom emoji
+        Node::Image(image) if image.url.starts_with("tg://emoji?id=") => with_entity(
+            image.alt.clone().into(),
+            MessageEntityKind::CustomEmoji {
+                custom_emoji_id: image.url["tg://emoji?id=".len()..].to_owned(),
+            },
+        ),
 
-            let entities = [entity].into_iter().chain(string.1).collect();
-        
-            StringWithEntities(string.0.clone(), entities)
+        // Telegram can't show images inline, so they become links with their description
+        Node::Image(image) => with_link(image_text(&image.alt, &image.url), &image.url),
+
+        Node::Blockquote(quote) => with_entity(
+            nodes_to_entities(&quote.children, ctx),
+            MessageEntityKind::Blockquote,
+        ),
+
+        Node::Table(table) => {
+            let mut string = with_entity(
+                table_to_text(table, ctx).into(),
+                MessageEntityKind::Pre { language: None },
+            );
+            string.0.push('\n' as u16);
+            string
         }
 
-        Node::Blockquote(quote) => {
-            let string = nodes_to_entities(quote.children.clone());
-            // there is no helper function for blockquotes
-            let entity = MessageEntity { kind: MessageEntityKind::Blockquote, offset: 0, length: string.0.len() };
-            let entities = [entity].into_iter().chain(string.1).collect();
+        Node::ThematicBreak(_) => "──────────\n".into(),
 
-            StringWithEntities(string.0.clone(), entities)
+        Node::FootnoteReference(footnote) => format!(
+            "[{}]",
+            footnote.label.as_ref().unwrap_or(&footnote.identifier)
+        )
+        .into(),
+
+        Node::FootnoteDefinition(footnote) => {
+            let mut string: StringWithEntities = format!(
+                "[{}]: ",
+                footnote.label.as_ref().unwrap_or(&footnote.identifier)
+            )
+            .into();
+            string.join(&nodes_to_entities(&footnote.children, ctx));
+            string
         }
 
         Node::Break(_) => "\n".into(),
 
-        _ => StringWithEntities(
-            format!("unknown node {node:#?}").encode_utf16().collect(),
-            vec![],
-        ),
+        // HTML, front matter and anything else Telegram has no equivalent for stays as it was typed
+        _ => ctx.source_of(node).into(),
     }
 }
 
-pub fn to_string_with_entities(value: &str) -> StringWithEntities {
-    // not perfect, but Rust's regex engine doesn't support look-arounds so :/
-    let re = Regex::new(r"\|\|(.*)\|\|");
-    let parsed_value = match re {
-        Ok(re) => {
-            re.replace(value, "[$1](x-oxibridge:spoiler)").into_owned()
+fn image_text(alt: &str, url: &str) -> StringWithEntities {
+    match alt.is_empty() {
+        true => format!("🖼 {url}").into(),
+        false => format!("🖼 {alt}").into(),
+    }
+}
+
+/// Lays a table out as plain text, with its columns lined up.
+fn table_to_text(table: &mdast::Table, ctx: &Context) -> String {
+    let rows: Vec<Vec<String>> = table
+        .children
+        .iter()
+        .map(|row| {
+            row.children()
+                .into_iter()
+                .flatten()
+                .map(|cell| String::from_utf16_lossy(&node_to_entities(cell, ctx).0))
+                .collect()
+        })
+        .collect();
+
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut lines = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        let line = widths
+            .iter()
+            .enumerate()
+            .map(|(column, &width)| {
+                format!("{:width$}", row.get(column).map_or("", String::as_str))
+            })
+            .collect::<Vec<_>>()
+            .join(" | ");
+        lines.push(line.trim_end().to_owned());
+
+        // the first row is the header
+        if i == 0 {
+            let rule = widths.iter().map(|&width| "-".repeat(width));
+            lines.push(rule.collect::<Vec<_>>().join("-+-"));
         }
-        Err(e) => {
-            debug!("regex creation failed: {e}");
-            value.to_owned()
+    }
+
+    lines.join("\n")
+}
+
+/// Converts Markdown into text and Telegram entities, falling back to the plain Markdown if it
+/// can't be parsed.
+pub fn to_string_with_entities(value: &str) -> StringWithEntities {
+    match try_to_string_with_entities(value) {
+        Ok(string) => string,
+        Err(report) => {
+            warn!(
+                ?report,
+                "could not parse Markdown, sending it as plain text"
+            );
+            value.into()
         }
-    };
+    }
+}
 
-    let node = markdown::to_mdast(&parsed_value, &ParseOptions::default()).unwrap();
-    node_to_entities(&node)
+fn try_to_string_with_entities(value: &str) -> Result<StringWithEntities> {
+    let value = preprocess(&value.replace(MARKERS, ""));
+    let node = markdown::to_mdast(&value, &parse_options())
+        .map_err(|why| eyre!("failed to parse Markdown: {why}"))?;
+    Ok(apply_markers(node_to_entities(
+        &node,
+        &Context::new(&value),
+    )))
+}
+
+/// CommonMark, narrowed down to what Discord supports so text isn't misread as something else.
+fn parse_options() -> ParseOptions {
+    ParseOptions {
+        constructs: Constructs {
+            // indented text, text over a line of dashes and link reference definitions are plain
+            // text on Discord
+            code_indented: false,
+            heading_setext: false,
+            definition: false,
+            ..Constructs::gfm()
+        },
+        gfm_strikethrough_single_tilde: false,
+        ..ParseOptions::gfm()
+    }
+}
+
+/// Code blocks and spans, in the order they have to be tried.
+const CODE: &str = r"```.*?```|``.*?``|`[^`]*`";
+
+/// Escaped characters and code, which are left for the parser, then spoilers (which may contain
+/// code) and subtext.
+static DISCORD_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r"(?ms)(\\.|{CODE})|\|\|((?:{CODE}|[^`]|`)+?)\|\||^-# ([^\n]+)$"
+    ))
+    .unwrap()
+});
+
+static BLANK_LINE_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\n[ \t]*\n").unwrap());
+
+/// Private use characters marking where spoilers and subtext start and end. CommonMark has no
+/// syntax for them, so the markers go through the parser as text and become entities after.
+const SPOILER_START: char = '\u{E000}';
+const SPOILER_END: char = '\u{E001}';
+const SUBTEXT_START: char = '\u{E002}';
+const SUBTEXT_END: char = '\u{E003}';
+const MARKERS: [char; 4] = [SPOILER_START, SPOILER_END, SUBTEXT_START, SUBTEXT_END];
+
+/// Surrounds Discord-only syntax with markers.
+fn preprocess(value: &str) -> String {
+    DISCORD_RE
+        .replace_all(value, |captures: &Captures| {
+            match (captures.get(2), captures.get(3)) {
+                (Some(spoiler), _) => {
+                    // entities can't span paragraphs, so each one gets its own spoiler
+                    let spoiler = BLANK_LINE_RE.replace_all(spoiler.as_str(), |line: &Captures| {
+                        format!("{SPOILER_END}{}{SPOILER_START}", &line[0])
+                    });
+                    format!("{SPOILER_START}{spoiler}{SPOILER_END}")
+                }
+                (_, Some(subtext)) => {
+                    format!(
+                        "{SUBTEXT_START}{}{SUBTEXT_END}",
+                        preprocess(subtext.as_str())
+                    )
+                }
+                _ => captures[0].to_owned(),
+            }
+        })
+        .into_owned()
+}
+
+/// Takes the markers out of the text, turning what they surround into entities.
+fn apply_markers(string: StringWithEntities) -> StringWithEntities {
+    let mut text = Vec::with_capacity(string.0.len());
+    // where each code unit ended up once the markers are gone, and where the text ends
+    let mut positions = Vec::with_capacity(string.0.len() + 1);
+    let mut starts = vec![];
+    let mut entities = vec![];
+
+    for &unit in &string.0 {
+        positions.push(text.len());
+        let kind = match char::from_u32(unit.into()) {
+            Some(SPOILER_START | SUBTEXT_START) => {
+                starts.push(text.len());
+                continue;
+            }
+            Some(SPOILER_END) => MessageEntityKind::Spoiler,
+            // Telegram has no small text, so subtext is set apart in italics
+            Some(SUBTEXT_END) => MessageEntityKind::Italic,
+            _ => {
+                text.push(unit);
+                continue;
+            }
+        };
+        match starts.pop() {
+            Some(offset) if offset < text.len() => entities.push(MessageEntity {
+                kind,
+                offset,
+                length: text.len() - offset,
+            }),
+            _ => (),
+        }
+    }
+    positions.push(text.len());
+
+    entities.extend(string.1.into_iter().filter_map(|entity| {
+        let offset = positions[entity.offset];
+        let length = positions[entity.offset + entity.length] - offset;
+        (length > 0).then_some(MessageEntity {
+            offset,
+            length,
+            ..entity
+        })
+    }));
+    // outer entities go first, as everywhere else
+    entities.sort_by_key(|entity| (entity.offset, Reverse(entity.length)));
+
+    StringWithEntities(text, entities)
 }
 
 #[cfg(test)]
@@ -215,7 +469,10 @@ mod tests {
     fn leaves_newlines_as_is() {
         let string = "hello\nworld";
 
-        assert_eq!(to_string_with_entities(string), (string.to_owned() + "\n").into());
+        assert_eq!(
+            to_string_with_entities(string),
+            (string.to_owned() + "\n").into()
+        );
     }
 
     #[test]
@@ -227,7 +484,7 @@ println!("hello, world!");
         assert_eq!(
             to_string_with_entities(string),
             StringWithEntities(
-                r#"println!("hello, world!");"#.encode_utf16().collect(),
+                "println!(\"hello, world!\");\n".encode_utf16().collect(),
                 vec![MessageEntity::pre(Some("rs".to_owned()), 0, 26)]
             )
         );
@@ -241,7 +498,9 @@ println!("hello, world!");
         assert_eq!(
             to_string_with_entities(string),
             StringWithEntities(
-                "\u{2022} hello\n\n\u{2022} there\n\n".encode_utf16().collect(),
+                "\u{2022} hello\n\n\u{2022} there\n\n"
+                    .encode_utf16()
+                    .collect(),
                 vec![]
             ),
         );
@@ -274,4 +533,167 @@ println!("hello, world!");
             ),
         );
     }
+
+    #[test]
+    fn parses_strikethrough_and_underline_correctly() {
+        let string = "~~gone~~ __under__";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                "gone under\n".encode_utf16().collect(),
+                vec![
+                    MessageEntity::strikethrough(0, 4),
+                    MessageEntity::underline(5, 5)
+                ]
+            ),
+        );
+    }
+
+    #[test]
+    fn parses_tables_as_pre() {
+        let string = "| a | bb |\n|---|---|\n| ccc | d |";
+        let table = "a   | bb\n----+---\nccc | d";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                format!("{table}\n").encode_utf16().collect(),
+                vec![MessageEntity::pre(None, 0, table.len())]
+            ),
+        );
+    }
+
+    #[test]
+    fn keeps_html_as_typed() {
+        let string = "<b>hi</b>";
+
+        assert_eq!(to_string_with_entities(string), "<b>hi</b>\n".into());
+    }
+
+    #[test]
+    fn counts_utf16_code_units() {
+        let string = "привет 👋 `код 👋`";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                "привет 👋 код 👋\n".encode_utf16().collect(),
+                vec![MessageEntity::code(10, 6)]
+            ),
+        );
+    }
+
+    #[test]
+    fn leaves_invalid_links_as_text() {
+        let string = "[relative](../somewhere)";
+
+        assert_eq!(to_string_with_entities(string), "relative\n".into());
+    }
+
+    #[test]
+    fn parses_discord_spoilers() {
+        let string = "||one|| and ||two\nlines|| but not \\||this||";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                "one and two\nlines but not ||this||\n"
+                    .encode_utf16()
+                    .collect(),
+                vec![MessageEntity::spoiler(0, 3), MessageEntity::spoiler(8, 9)]
+            ),
+        );
+    }
+
+    #[test]
+    fn parses_discord_spoilers_around_markdown() {
+        assert_eq!(
+            to_string_with_entities("||a]b||"),
+            StringWithEntities(
+                "a]b\n".encode_utf16().collect(),
+                vec![MessageEntity::spoiler(0, 3)]
+            ),
+        );
+        assert_eq!(
+            to_string_with_entities("||see [x](https://a.b)||"),
+            StringWithEntities(
+                "see x\n".encode_utf16().collect(),
+                vec![
+                    MessageEntity::spoiler(0, 5),
+                    MessageEntity::text_link(reqwest::Url::parse("https://a.b").unwrap(), 4, 1)
+                ]
+            ),
+        );
+        assert_eq!(
+            to_string_with_entities("||one\n\ntwo||"),
+            StringWithEntities(
+                "one\ntwo\n".encode_utf16().collect(),
+                vec![MessageEntity::spoiler(0, 3), MessageEntity::spoiler(4, 3)]
+            ),
+        );
+    }
+
+    #[test]
+    fn leaves_discord_syntax_in_code() {
+        let string = "`||not||` ||yes `||`||\n```\n||no||\n-# no\n```";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                "||not|| yes ||\n||no||\n-# no\n".encode_utf16().collect(),
+                vec![
+                    MessageEntity::code(0, 7),
+                    MessageEntity::spoiler(8, 6),
+                    MessageEntity::code(12, 2),
+                    MessageEntity::pre(None, 15, 12),
+                ]
+            ),
+        );
+    }
+
+    #[test]
+    fn parses_discord_subtext_and_headings() {
+        let string = "# big\n-# small\n#### not a heading";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                "# big\nsmall\n#### not a heading\n"
+                    .encode_utf16()
+                    .collect(),
+                vec![MessageEntity::bold(0, 5), MessageEntity::italic(6, 5)]
+            ),
+        );
+    }
+
+    #[test]
+    fn parses_discord_links_and_plain_text() {
+        let string = "[hi](<https://itsvic.dev>) ~not~\n    indented";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                "hi ~not~\nindented\n".encode_utf16().collect(),
+                vec![MessageEntity::text_link(
+                    reqwest::Url::parse("https://itsvic.dev").unwrap(),
+                    0,
+                    2
+                )]
+            ),
+        );
+    }
+
+    #[test]
+    fn parses_custom_emoji() {
+        let string = "hi ![😺](tg://emoji?id=42)";
+
+        assert_eq!(
+            to_string_with_entities(string),
+            StringWithEntities(
+                "hi 😺\n".encode_utf16().collect(),
+                vec![MessageEntity::custom_emoji("42".to_owned(), 3, 2)]
+            ),
+        );
+    }
 }
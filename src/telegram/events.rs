@@ -1,19 +1,30 @@
 use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::eyre;
-use teloxide::{types::Message, Bot};
+use teloxide::{
+    payloads::SendMessageSetters,
+    prelude::Requester,
+    types::{Message, MessageId, Poll, ReplyParameters},
+    Bot,
+};
 use tokio::sync::Mutex;
 use tracing::*;
 
 use crate::{
     broadcast::{Broadcaster, MessageEvent, Source},
     config::GroupConfig,
-    telegram::to_core_message,
+    core,
+    state::{NameChat, State, Topic, NAME_COMMAND},
+    telegram::{to_core_message, to_core_poll, Fetched},
     Config,
 };
 
 use super::TgCache;
 
+/// Commands that delete the bridged message they reply to, here and on the other platforms.
+const DELETE_COMMANDS: [&str; 2] = ["/delete", "/unbridge"];
+
 #[instrument(skip_all)]
 pub async fn message_handle(
     bot: Bot,
@@ -21,6 +32,7 @@ pub async fn message_handle(
     config: Arc<Config>,
     cache: Arc<Mutex<TgCache>>,
     broadcaster: Arc<Mutex<Broadcaster>>,
+    state: Arc<Mutex<State>>,
 ) -> color_eyre::Result<()> {
     // find the respective group
     let group: Vec<GroupConfig> = config
@@ -35,6 +47,48 @@ pub async fn message_handle(
         None => return Ok(()),
     };
 
+    let thread = match message.thread_id {
+        Some(thread) if group.forum_topics && message.is_topic_message => Some(thread.0 .0),
+        _ => None,
+    };
+
+    // topic changes are mirrored instead of being bridged as messages
+    if let Some(thread) = thread {
+        let name = match (message.forum_topic_created(), message.forum_topic_edited()) {
+            (Some(created), _) => Some(created.name.clone()),
+            (None, Some(edited)) => edited.name.clone(),
+            (None, None) => None,
+        };
+
+        if message.forum_topic_created().is_some() || message.forum_topic_edited().is_some() {
+            let id = core_topic(&state, group, message.chat.id.0, thread, name.as_deref()).await?;
+            broadcaster
+                .lock()
+                .await
+                .broadcast(group, &MessageEvent::TopicUpdate(id), Source::Telegram)
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let command = message
+        .text()
+        .and_then(|text| text.split_whitespace().next())
+        .and_then(|command| command.split('@').next());
+
+    if command == Some(NAME_COMMAND) {
+        return name_command(bot, &message, &state).await;
+    }
+
+    // the Bot API doesn't tell bots about deletions, so they're requested with a command instead
+    if let Some(target) = message.reply_to_message() {
+        if target.forum_topic_created().is_none()
+            && command.is_some_and(|command| DELETE_COMMANDS.contains(&command))
+        {
+            return delete_command(bot, &message, target, group, cache, broadcaster).await;
+        }
+    }
+
     // look up reply in cache
     let cached_reply = match message.reply_to_message() {
         Some(msg) => cache.lock().await.tg_core_cache.get(&msg.id).cloned(),
@@ -47,7 +101,28 @@ pub async fn message_handle(
         None => (None, None),
     };
 
-    let core_message = to_core_message(bot, &message, reply_id, reply_author).await?;
+    let mut fetched = Fetched::default();
+    let mut core_message = to_core_message(
+        bot,
+        &message,
+        reply_id,
+        reply_author,
+        group,
+        &config.shared,
+        &mut fetched,
+    )
+    .await?;
+    display_name(&state, &message, &mut core_message.author).await;
+
+    if let Some(thread) = thread {
+        // messages in a topic reply to the message that created it, which has the topic's name
+        let name = message
+            .reply_to_message()
+            .and_then(|reply| reply.forum_topic_created())
+            .map(|created| created.name.as_str());
+        core_message.topic =
+            Some(core_topic(&state, group, message.chat.id.0, thread, name).await?);
+    }
 
     {
         let mut cache = cache.lock().await;
@@ -57,6 +132,38 @@ pub async fn message_handle(
         cache
             .core_tg_cache
             .insert(core_message.id, (message.id, String::new()));
+        cache.keep_fetched(message.id, fetched);
+        if let Some(poll) = message.poll() {
+            cache
+                .poll_core_cache
+                .insert(poll.id.clone(), (message.chat.id, core_message.id));
+        }
+    }
+
+    if let core::ContentKind::Location(
+        location @ core::Location {
+            live: Some(live), ..
+        },
+    ) = core_message.kind
+    {
+        cache
+            .lock()
+            .await
+            .live_locations
+            .insert(message.id, location);
+
+        // Telegram doesn't say when a live location runs out, so end it ourselves
+        if let core::LiveLocation::Until(until) = live {
+            tokio::spawn(expire_live_location(
+                message.id,
+                core_message.id,
+                until,
+                group.clone(),
+                config.clone(),
+                cache.clone(),
+                broadcaster.clone(),
+            ));
+        }
     }
 
     broadcaster
@@ -68,12 +175,131 @@ pub async fn message_handle(
     Ok(())
 }
 
+/// Deletes a bridged message everywhere, if the command comes from its author or an admin.
+async fn delete_command(
+    bot: Bot,
+    message: &Message,
+    target: &Message,
+    group: &GroupConfig,
+    cache: Arc<Mutex<TgCache>>,
+    broadcaster: Arc<Mutex<Broadcaster>>,
+) -> color_eyre::Result<()> {
+    let core_id = match cache.lock().await.tg_core_cache.get(&target.id) {
+        Some((id, _)) => *id,
+        None => {
+            bot.send_message(message.chat.id, "That message isn't bridged.")
+                .reply_parameters(ReplyParameters::new(message.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // anonymous admins send as the chat itself
+    let anonymous_admin = message
+        .sender_chat
+        .as_ref()
+        .is_some_and(|chat| chat.id == message.chat.id);
+    let allowed = match &message.from {
+        _ if anonymous_admin => true,
+        Some(user) if target.from.as_ref().is_some_and(|from| from.id == user.id) => true,
+        Some(user) => bot
+            .get_chat_member(message.chat.id, user.id)
+            .await?
+            .is_privileged(),
+        None => false,
+    };
+    if !allowed {
+        bot.send_message(
+            message.chat.id,
+            "Only the author or an admin can delete this message.",
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await?;
+        return Ok(());
+    }
+
+    bot.delete_message(message.chat.id, target.id).await?;
+    if let Err(why) = bot.delete_message(message.chat.id, message.id).await {
+        debug!(?why, "could not clean up the delete command");
+    }
+
+    broadcaster
+        .lock()
+        .await
+        .broadcast(group, &MessageEvent::Delete(core_id), Source::Telegram)
+        .await?;
+
+    Ok(())
+}
+
+/// Sets the name the sender shows up as on the other platforms, or resets it if none is given.
+async fn name_command(bot: Bot, message: &Message, state: &Mutex<State>) -> color_eyre::Result<()> {
+    // anonymous admins and channels all share the same placeholder user
+    let user = match &message.from {
+        Some(user) if message.sender_chat.is_none() => user,
+        _ => return Ok(()),
+    };
+
+    let reply = {
+        let mut state = state.lock().await;
+        let reply = state.name_command(
+            NameChat::Telegram(message.chat.id.0),
+            user.id.0,
+            message.text().unwrap_or_default(),
+        );
+        state.save().await?;
+        reply
+    };
+
+    bot.send_message(message.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Shows the author of a message under the name they picked with the name command, if any.
+async fn display_name(state: &Mutex<State>, message: &Message, author: &mut core::Author) {
+    let user = match &message.from {
+        Some(user) => user.id.0,
+        None => return,
+    };
+    if let Some(name) = state
+        .lock()
+        .await
+        .display_name(NameChat::Telegram(message.chat.id.0), user)
+    {
+        author.display_name = Some(name.to_owned());
+    }
+}
+
 #[instrument(skip_all)]
 pub async fn message_edit_handle(
+    bot: Bot,
     message: Message,
     config: Arc<Config>,
     cache: Arc<Mutex<TgCache>>,
     broadcaster: Arc<Mutex<Broadcaster>>,
+    state: Arc<Mutex<State>>,
+) -> color_eyre::Result<()> {
+    // updates from a chat are handled one after another, so waiting out the quiet period here
+    // would hold up everything else sent in the meantime
+    tokio::spawn(async move {
+        if let Err(why) = bridge_edit(bot, message, config, cache, broadcaster, state).await {
+            error!(?why, "Failed to bridge edit");
+        }
+    });
+
+    Ok(())
+}
+
+async fn bridge_edit(
+    bot: Bot,
+    message: Message,
+    config: Arc<Config>,
+    cache: Arc<Mutex<TgCache>>,
+    broadcaster: Arc<Mutex<Broadcaster>>,
+    state: Arc<Mutex<State>>,
 ) -> color_eyre::Result<()> {
     // find the respective group
     let group: Vec<GroupConfig> = config
@@ -96,17 +322,198 @@ pub async fn message_edit_handle(
         None => return Err(eyre!("failed to get core ID from {:?}", message.id)),
     };
 
-    let text = message.text().unwrap_or(message.caption().unwrap_or(""));
+    let pending_edits = broadcaster.lock().await.pending_edits();
+    let Some(edit) = pending_edits.settle(core_id).await else {
+        return Ok(());
+    };
+
+    // edits go through the same parsing as new messages, only fetching what changed
+    let mut fetched = match cache.lock().await.fetched.remove(&message.id) {
+        Some(fetched) => fetched.for_edit(),
+        None => Fetched::default(),
+    };
+    let mut core_message = to_core_message(
+        bot,
+        &message,
+        None,
+        None,
+        group,
+        &config.shared,
+        &mut fetched,
+    )
+    .await?;
+    cache.lock().await.keep_fetched(message.id, fetched);
+    display_name(&state, &message, &mut core_message.author).await;
+    core_message.id = core_id;
+
+    // live locations get edited with every position update
+    if let core::ContentKind::Location(location) = &mut core_message.kind {
+        let mut cache = cache.lock().await;
+        match location.live {
+            Some(_) => {
+                cache.live_locations.insert(message.id, *location);
+            }
+            // sharing stopped, so the last position is final
+            None if cache.live_locations.remove(&message.id).is_some() => {
+                location.live = Some(core::LiveLocation::Ended);
+                core_message.content = location.to_markdown(config.shared.map_provider);
+            }
+            None => (),
+        }
+    }
+
+    broadcaster
+        .lock()
+        .await
+        .broadcast_edit(
+            group,
+            edit,
+            MessageEvent::Update(core_message),
+            Source::Telegram,
+        )
+        .await
+}
+
+/// Handles new vote counts and closures of bridged polls.
+///
+/// Telegram only reports these for polls sent by the bot itself, or when a poll gets closed.
+#[instrument(skip_all)]
+pub async fn poll_handle(
+    poll: Poll,
+    config: Arc<Config>,
+    cache: Arc<Mutex<TgCache>>,
+    broadcaster: Arc<Mutex<Broadcaster>>,
+) -> color_eyre::Result<()> {
+    let (chat_id, core_id) = match cache.lock().await.poll_core_cache.get(&poll.id) {
+        Some(ids) => *ids,
+        None => return Ok(()),
+    };
+
+    // find the respective group
+    let group = match config
+        .groups
+        .iter()
+        .find(|g| g.telegram_chat == Some(chat_id.0))
+    {
+        Some(group) => group,
+        None => return Ok(()),
+    };
 
     broadcaster
         .lock()
         .await
         .broadcast(
             group,
-            &MessageEvent::Update(core_id, text.to_owned()),
+            &MessageEvent::PollUpdate(core_id, to_core_poll(&poll)),
             Source::Telegram,
         )
         .await?;
 
     Ok(())
 }
+
+/// Finds the core topic of a Telegram forum topic, adding it to the state if it's new.
+///
+/// A name, if given, replaces the topic's current one.
+async fn core_topic(
+    state: &Mutex<State>,
+    group: &GroupConfig,
+    chat: i64,
+    thread: i32,
+    name: Option<&str>,
+) -> color_eyre::Result<u64> {
+    let mut state = state.lock().await;
+
+    let id = match state.telegram_topic(chat, thread) {
+        Some(topic) if name.is_none_or(|name| name == topic.name) => return Ok(topic.id),
+        Some(topic) => topic.id,
+        None => {
+            let id = state.next_topic_id();
+            state.add_topic(Topic {
+                id,
+                name: format!("Topic {thread}"),
+                telegram_chat: Some(chat),
+                telegram_thread: Some(thread),
+                discord_channel: group.discord.as_ref().map(|dsc| dsc.channel),
+                discord_thread: None,
+            });
+            id
+        }
+    };
+
+    if let (Some(topic), Some(name)) = (state.topic_mut(id), name) {
+        name.clone_into(&mut topic.name);
+    }
+    state.save().await?;
+
+    Ok(id)
+}
+
+/// Marks a live location as ended once its live period is over, showing its final position.
+#[instrument(skip(group, config, cache, broadcaster))]
+async fn expire_live_location(
+    message_id: MessageId,
+    core_id: u64,
+    until: DateTime<Utc>,
+    group: GroupConfig,
+    config: Arc<Config>,
+    cache: Arc<Mutex<TgCache>>,
+    broadcaster: Arc<Mutex<Broadcaster>>,
+) {
+    let mut until = until;
+    let mut location = loop {
+        let remaining = (until - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(remaining).await;
+
+        let mut cache = cache.lock().await;
+        let location = match cache.live_locations.get(&message_id) {
+            Some(location) => *location,
+            // already gone if it was stopped early
+            None => return,
+        };
+
+        match location.live {
+            // the live period got extended in the meantime
+            Some(core::LiveLocation::Until(extended)) if extended > Utc::now() => until = extended,
+            _ => {
+                cache.live_locations.remove(&message_id);
+                break location;
+            }
+        }
+    };
+    location.live = Some(core::LiveLocation::Ended);
+
+    let pending_edits = broadcaster.lock().await.pending_edits();
+    let Some(edit) = pending_edits.settle(core_id).await else {
+        return;
+    };
+
+    let author = match cache.lock().await.tg_core_cache.get(&message_id) {
+        Some((_, author)) => author.clone(),
+        None => return,
+    };
+    let mut core_message = core::Message::new(
+        author.into(),
+        location.to_markdown(config.shared.map_provider),
+        vec![],
+        None,
+        None,
+    )
+    .await;
+    core_message.id = core_id;
+    core_message.kind = core::ContentKind::Location(location);
+
+    if let Err(why) = broadcaster
+        .lock()
+        .await
+        .broadcast_edit(
+            &group,
+            edit,
+            MessageEvent::Update(core_message),
+            Source::Telegram,
+        )
+        .await
+    {
+        error!(?why, "Failed to bridge expired live location");
+    }
+}
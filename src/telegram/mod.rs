@@ -1,24 +1,43 @@
 use std::{collections::HashMap, sync::Arc};
-use teloxide::{prelude::*, types::MessageId};
+use teloxide::{
+    prelude::*,
+    types::{ChatId, MessageId},
+};
 use tokio::sync::Mutex;
 use tracing::*;
 
-use crate::core::PartialAuthor;
-use crate::{broadcast::Broadcaster, Config};
+use crate::core::{self, PartialAuthor};
+use crate::{broadcast::Broadcaster, state::State, storage::R2Storage, Config};
 
 mod broadcast;
 mod entities;
 mod events;
 mod parsers;
+mod stickers;
 mod unparse;
 use self::events::*;
 use self::parsers::*;
 
+/// Largest file the cloud Bot API lets bots download. Local servers have no limit.
+const DOWNLOAD_LIMIT: u64 = 20 * 1024 * 1024;
+
+/// How many messages to keep fetched files around for, to reuse when they're edited.
+const FETCHED_LIMIT: usize = 64;
+
+/// Largest file the cloud Bot API lets bots upload.
+const UPLOAD_LIMIT: u64 = 50 * 1024 * 1024;
+
+/// Largest file a local Bot API server lets bots upload.
+const LOCAL_UPLOAD_LIMIT: u64 = 2000 * 1024 * 1024;
+
 pub struct TelegramBridge {
     pub bot: Bot,
 
+    storage: Option<Arc<Mutex<R2Storage>>>,
+
     broadcaster: Arc<Mutex<Broadcaster>>,
     config: Arc<Config>,
+    state: Arc<Mutex<State>>,
 
     cache: Arc<Mutex<TgCache>>,
 }
@@ -30,11 +49,64 @@ struct TgCache {
 
     /// Cache of core message IDs to (Telegram IDs, author names).
     core_tg_cache: HashMap<u64, (MessageId, String)>,
+
+    /// Cache of Telegram poll IDs to (chat IDs, core message IDs).
+    poll_core_cache: HashMap<String, (ChatId, u64)>,
+
+    /// Cache of core message IDs to the messages summarizing their poll's votes.
+    poll_summaries: HashMap<u64, MessageId>,
+
+    /// Cache of Telegram IDs to the latest state of live locations that are still being shared.
+    live_locations: HashMap<MessageId, core::Location>,
+
+    /// Cache of core message IDs to how they were sent and edited, for messages sent by the bridge.
+    rendered: HashMap<u64, Rendered>,
+
+    /// Cache of Telegram IDs to what was fetched to parse them, for the latest messages.
+    fetched: HashMap<MessageId, Fetched>,
+}
+
+/// A bridged message as sent, to compare edits against and show their history.
+#[derive(Debug, Clone)]
+struct Rendered {
+    sent_as: SentAs,
+    /// Every version of the core content, oldest first.
+    versions: Vec<String>,
+    attachment_hashes: Vec<String>,
+}
+
+/// What kind of Telegram message a bridged message was sent as, which decides how it's edited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SentAs {
+    Text,
+    /// A single photo, video or file, with a caption instead of text.
+    Media,
+    /// An album, of which only the first item has the caption.
+    MediaGroup,
+    /// A poll, location, venue or contact, which can't be edited.
+    Native,
+}
+
+impl TgCache {
+    /// Keeps what was fetched for a message, dropping the oldest message's over the limit.
+    fn keep_fetched(&mut self, id: MessageId, fetched: Fetched) {
+        self.fetched.insert(id, fetched);
+        if self.fetched.len() > FETCHED_LIMIT {
+            if let Some(oldest) = self.fetched.keys().min_by_key(|id| id.0).copied() {
+                self.fetched.remove(&oldest);
+            }
+        }
+    }
 }
 
 impl TelegramBridge {
     #[instrument(skip_all)]
-    pub fn init(broadcaster: Arc<Mutex<Broadcaster>>, config: Arc<Config>) -> TelegramBridge {
+    pub fn init(
+        broadcaster: Arc<Mutex<Broadcaster>>,
+        config: Arc<Config>,
+        storage: Option<Arc<Mutex<R2Storage>>>,
+        state: Arc<Mutex<State>>,
+    ) -> TelegramBridge {
         debug!("Creating Telegram bot");
         let bot = Bot::new(
             config
@@ -44,14 +116,29 @@ impl TelegramBridge {
                 .expect("Telegram token not configured in shared config"),
         );
 
+        let bot = match &config.shared.telegram_api_url {
+            Some(url) => {
+                debug!(url, "using custom Bot API server");
+                bot.set_api_url(url.parse().expect("Invalid Telegram API URL"))
+            }
+            None => bot,
+        };
+
         TelegramBridge {
             bot,
+            storage,
             broadcaster,
             config,
+            state,
 
             cache: Arc::new(Mutex::new(TgCache {
                 core_tg_cache: HashMap::new(),
                 tg_core_cache: HashMap::new(),
+                poll_core_cache: HashMap::new(),
+                poll_summaries: HashMap::new(),
+                live_locations: HashMap::new(),
+                rendered: HashMap::new(),
+                fetched: HashMap::new(),
             })),
         }
     }
@@ -59,12 +146,14 @@ impl TelegramBridge {
     pub async fn start(&self) {
         let handler = dptree::entry()
             .branch(Update::filter_message().endpoint(message_handle))
-            .branch(Update::filter_edited_message().endpoint(message_edit_handle));
+            .branch(Update::filter_edited_message().endpoint(message_edit_handle))
+            .branch(Update::filter_poll().endpoint(poll_handle));
         Dispatcher::builder(self.bot.clone(), handler)
             .dependencies(dptree::deps![
                 self.config.clone(),
                 self.broadcaster.clone(),
-                self.cache.clone()
+                self.cache.clone(),
+                self.state.clone()
             ])
             .build()
             .dispatch()
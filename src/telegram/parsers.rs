@@ -1,136 +1,29 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path, time::Duration};
 
-use crate::{broadcast::Source, core::{self, PartialAuthor}};
+use crate::{broadcast::Source, config::{GroupConfig, SharedConfig}, core::{self, PartialAuthor}};
 use async_tempfile::TempFile;
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncWriteExt;
 use teloxide::{
     net::Download,
     prelude::*,
-    types::{self, Dice, Location, MediaKind, MessageKind, MessageOrigin, PhotoSize},
+    types::{
+        self, FileMeta, Location, MediaKind, MessageEntityKind, MessageKind, MessageOrigin,
+        PhotoSize,
+    },
 };
 use tracing::*;
 
-use super::unparse::unparse_entities;
-
-pub fn serialize_die_value(die: Dice) -> String {
-    match die.emoji {
-        types::DiceEmoji::Dice => match die.value {
-            1 => "⚀".to_string(),
-            2 => "⚁".to_string(),
-            3 => "⚂".to_string(),
-            4 => "⚃".to_string(),
-            5 => "⚄".to_string(),
-            6 => "⚅".to_string(),
-            _ => "🎲".to_string(),
-        },
-        types::DiceEmoji::Darts => {
-            tracing::debug!("Darts dice value: {}", die.value);
-
-            // 1 is miss
-            // 2 is outer double
-            // 3 is outer single
-            // 4 is triple ring
-            // 5 is inner single
-            // 6 is bullseye
-
-            //  a value of 6 currently represents a bullseye, while a value of 1 indicates that the dartboard was missed.
-            match die.value {
-                1 => "🎯❌ Miss!",
-                2 => "🎯🎯 Outer double!",
-                3 => "🎯🎯 Outer single!",
-                4 => "🎯🎯🎯 Triple ring!",
-                5 => "🎯🎯 Inner single!",
-                6 => "🎯🎯🎯 Bullseye!",
-                _ => "🎯",
-            }
-            .to_string()
-        }
-        types::DiceEmoji::Bowling => {
-            tracing::debug!("Bowling dice value: {}", die.value);
-
-            // 1 is right gutter
-            // 2 is left taking down only 1 pin
-            // 3 is mid-left, taking down 3 pins
-            // 4 is mid-right, taking down 4 pins
-            // 5 is spare (taking down 5 pins)
-            // 6 is strike
-            // let's do the math
-
-            match die.value {
-                1 => "🎳❌ Gutter!",
-                2 => "🎳🫡 1 pin down",
-                3 => "🎳🫡 Split! 3 pins down",
-                4 => "🎳🫡 4 pins down",
-                5 => "🎳🫡 Spare! 5 pins down",
-                6 => "🎳🎳 Strike!",
-                _ => "🎳",
-            }
-            .to_string()
-        }
-        types::DiceEmoji::Basketball => {
-            // should be similar to football
-
-            tracing::debug!("Basketball dice value: {}", die.value);
-            let dunk = die.value >= 4;
-            if dunk {
-                "🏀🔥".to_string()
-            } else {
-                "🏀❌".to_string()
-            }
-        }
-        types::DiceEmoji::Football => {
-            tracing::debug!("Football dice value: {}", die.value);
-            // let's document the mapping here..
-
-            // If emoji is “⚽”, a value of 4 to 5 currently scores a goal, while a value of 1 to 3
-            // indicates that the goal was missed. However, this behaviour is undocumented and might be changed by Telegram.
-
-            // 1 is overshot (miss)
-            // 2 is top left miss
-            // 3 is middle goal?
-            // 4 is left goal, hitting right post and going in net
-            // 5 is top right goal
-
-            let goal = die.value >= 3;
-            if goal {
-                "⚽️🥅".to_string()
-            } else {
-                "⚽️❌".to_string()
-            }
-        }
-        types::DiceEmoji::SlotMachine => {
-            let values: [u8; 3] = [
-                (die.value - 1) & 3,
-                ((die.value - 1) >> 2) & 3,
-                ((die.value - 1) >> 4) & 3,
-            ];
-
-            // map those values to the correct slot emoji
-
-            fn slot(value: u8) -> String {
-                match value {
-                    // 0 is BAR
-                    0 => "⬛",
-                    1 => "🍇",
-                    2 => "🍋",
-                    3 => "7️⃣",
-                    _ => "🎰",
-                }
-                .to_string()
-            }
+use super::{stickers::convert_sticker, unparse::unparse_entities, DOWNLOAD_LIMIT};
 
-            let value = format!(
-                "[{} {} {}]",
-                slot(values[0]),
-                slot(values[1]),
-                slot(values[2])
-            );
-
-            if values.iter().all(|&v| v == 3) {
-                format!("{value} 🎉🎉🎉 JACKPOT!!! 🎉🎉🎉", value = value)
-            } else {
-                value
-            }
-        }
+fn dice_emoji(emoji: &types::DiceEmoji) -> &'static str {
+    match emoji {
+        types::DiceEmoji::Dice => "🎲",
+        types::DiceEmoji::Darts => "🎯",
+        types::DiceEmoji::Bowling => "🎳",
+        types::DiceEmoji::Basketball => "🏀",
+        types::DiceEmoji::Football => "⚽",
+        types::DiceEmoji::SlotMachine => "🎰",
     }
 }
 
@@ -140,169 +33,275 @@ pub async fn to_core_message(
     m: &Message,
     in_reply_to: Option<u64>,
     reply_author: Option<PartialAuthor>,
+    group: &GroupConfig,
+    shared: &SharedConfig,
+    fetched: &mut Fetched,
 ) -> color_eyre::Result<core::Message> {
+    let local = shared.telegram_local_mode;
     let tg_author = match m.from.as_ref() {
         Some(author) => author,
         None => return Err(color_eyre::eyre::eyre!("Message has no author")),
     };
-    let core_author = to_core_author(bot.clone(), tg_author).await?;
 
-    let (content, attachments) = match &m.kind {
+    // edits reuse the author of the original message, as the avatar isn't needed again
+    let core_author = match &fetched.author {
+        Some(author) => author.clone().into(),
+        None => {
+            let author = to_core_author(bot.clone(), tg_author, local).await?;
+            fetched.author = Some((&author).into());
+            author
+        }
+    };
+
+    // notes about files that couldn't be bridged
+    let mut notes: Vec<String> = vec![];
+
+    // set by the branches for content other platforms might recreate natively
+    let mut kind = core::ContentKind::Text;
+
+    let (emoji, emoji_attachments) =
+        custom_emoji(bot.clone(), m, group, &mut notes, fetched, local).await;
+    let unparse = |text: &str, entities| unparse_entities(text, entities, &emoji);
+
+    let (content, mut attachments) = match &m.kind {
         MessageKind::Common(common) => match &common.media_kind {
             // MediaKind::Text(text) => (text.text.to_owned(), vec![]),
-            MediaKind::Text(text) => (unparse_entities(&text.text, text.entities.clone()), vec![]),
+            MediaKind::Text(text) => (unparse(&text.text, text.entities.clone()), vec![]),
 
             MediaKind::Photo(photo) => {
-                let attachment = photo_to_core_file(bot.clone(), &photo.photo).await?;
+                let size = photo.photo.last();
+                let attachment = match size {
+                    Some(size) => fetched.file(bot.clone(), &size.file, local).await?,
+                    None => return Err(color_eyre::eyre::eyre!("No photo found")),
+                };
                 (
-                    unparse_entities(
+                    unparse(
                         &photo.caption.clone().unwrap_or("".to_string()), photo.caption_entities.clone()
                     ),
                     vec![core::Attachment {
                         file: attachment,
                         spoilered: photo.has_media_spoiler,
                         filename: String::new(),
+                        mime_type: Some("image/jpeg".to_owned()),
+                        width: size.map(|size| size.width),
+                        height: size.map(|size| size.height),
+                        duration: None,
+                        description: None,
+                        voice: false,
+                        waveform: None,
                     }],
                 )
             }
 
             MediaKind::Video(video) => {
-                let file = bot.get_file(&video.video.file.id).await?;
-                let attachment = to_core_file(bot.clone(), &file).await?;
+                let attachments = to_core_attachments(
+                    bot.clone(),
+                    &video.video.file,
+                    "video",
+                    AttachmentInfo {
+                        filename: video.video.file_name.clone(),
+                        mime_type: video.video.mime_type.as_ref().map(ToString::to_string),
+                        width: Some(video.video.width),
+                        height: Some(video.video.height),
+                        duration: Some(video.video.duration.duration()),
+                        spoilered: video.has_media_spoiler,
+                        ..Default::default()
+                    },
+                    &mut notes,
+                    fetched,
+                    local,
+                )
+                .await?;
                 (
-                    unparse_entities(
+                    unparse(
                         &video.caption.clone().unwrap_or("".to_string()), video.caption_entities.clone()
                     ),
-                    vec![core::Attachment {
-                        file: attachment,
-                        spoilered: video.has_media_spoiler,
-                        filename: String::new(),
-                    }],
+                    attachments,
                 )
             }
 
             MediaKind::Audio(audio) => {
-                let file = bot.get_file(&audio.audio.file.id).await?;
-                let attachment = to_core_file(bot.clone(), &file).await?;
+                let attachments = to_core_attachments(
+                    bot.clone(),
+                    &audio.audio.file,
+                    "audio",
+                    AttachmentInfo {
+                        filename: audio.audio.file_name.clone(),
+                        mime_type: audio.audio.mime_type.as_ref().map(ToString::to_string),
+                        duration: Some(audio.audio.duration.duration()),
+                        ..Default::default()
+                    },
+                    &mut notes,
+                    fetched,
+                    local,
+                )
+                .await?;
                 (
-                    unparse_entities(
+                    unparse(
                         &audio.caption.clone().unwrap_or("".to_string()), audio.caption_entities.clone()
                     ),
-                    vec![core::Attachment {
-                        file: attachment,
-                        spoilered: false,
-                        filename: String::new(),
-                    }],
+                    attachments,
                 )
             }
 
             MediaKind::Voice(voice) => {
-                let file = bot.get_file(&voice.voice.file.id).await?;
-                let attachment = to_core_file(bot.clone(), &file).await?;
+                let attachments = to_core_attachments(
+                    bot.clone(),
+                    &voice.voice.file,
+                    "voice message",
+                    AttachmentInfo {
+                        mime_type: voice.voice.mime_type.as_ref().map(ToString::to_string),
+                        duration: Some(voice.voice.duration.duration()),
+                        voice: true,
+                        ..Default::default()
+                    },
+                    &mut notes,
+                    fetched,
+                    local,
+                )
+                .await?;
                 (
-                    "*Voice message*\n".to_string() + &unparse_entities(
+                    unparse(
                         &voice.caption.clone().unwrap_or("".to_string()), voice.caption_entities.clone()
                     ),
-                    vec![core::Attachment {
-                        file: attachment,
-                        spoilered: false,
-                        filename: String::new(),
-                    }],
+                    attachments,
                 )
             }
 
             MediaKind::VideoNote(note) => {
-                let file = bot.get_file(&note.video_note.file.id).await?;
-                let attachment = to_core_file(bot.clone(), &file).await?;
+                let attachments = to_core_attachments(
+                    bot.clone(),
+                    &note.video_note.file,
+                    "video note",
+                    AttachmentInfo {
+                        mime_type: Some("video/mp4".to_owned()),
+                        width: Some(note.video_note.length),
+                        height: Some(note.video_note.length),
+                        duration: Some(note.video_note.duration.duration()),
+                        ..Default::default()
+                    },
+                    &mut notes,
+                    fetched,
+                    local,
+                )
+                .await?;
                 (
                     "*Video note*".to_string(),
-                    vec![core::Attachment {
-                        file: attachment,
-                        spoilered: false,
-                        filename: String::new(),
-                    }],
+                    attachments,
                 )
             }
 
-            MediaKind::Location(location) => {        
-                (
-                    format!("Shared location: {}", location_url(&location.location)),
-                    vec![],
-                )
+            MediaKind::Location(location) => {
+                let location = to_core_location(&location.location, m.date);
+                let content = location.to_markdown(shared.map_provider);
+                kind = core::ContentKind::Location(location);
+                (content, vec![])
             }
 
             MediaKind::Venue(venue) => {
-                let url = match &venue.venue.google_place_id {
-                    Some(id) => location_url_with_place_id(&venue.venue.location, id),
-                    None => location_url(&venue.venue.location)
+                let venue = core::Venue {
+                    location: to_core_location(&venue.venue.location, m.date),
+                    title: venue.venue.title.clone(),
+                    address: venue.venue.address.clone(),
+                    google_place_id: venue.venue.google_place_id.clone(),
                 };
-                (
-                    format!("*Shared a venue*\n**{}**\n{}\n{}", venue.venue.title, venue.venue.address, url),
-                    vec![],
-                )
+                let content = venue.to_markdown(shared.map_provider);
+                kind = core::ContentKind::Venue(venue);
+                (content, vec![])
+            }
+
+            MediaKind::Contact(contact) => {
+                let contact = core::Contact {
+                    phone_number: contact.contact.phone_number.clone(),
+                    first_name: contact.contact.first_name.clone(),
+                    last_name: contact.contact.last_name.clone(),
+                };
+                let content = contact.to_markdown();
+                kind = core::ContentKind::Contact(contact);
+                (content, vec![])
             }
 
             MediaKind::Animation(animation) => {
-                let file = bot.get_file(&animation.animation.file.id).await?;
-                let attachment = to_core_file(bot.clone(), &file).await?;
+                let attachments = to_core_attachments(
+                    bot.clone(),
+                    &animation.animation.file,
+                    "animation",
+                    AttachmentInfo {
+                        filename: animation.animation.file_name.clone(),
+                        mime_type: animation.animation.mime_type.as_ref().map(ToString::to_string),
+                        width: Some(animation.animation.width),
+                        height: Some(animation.animation.height),
+                        duration: Some(animation.animation.duration.duration()),
+                        spoilered: animation.has_media_spoiler,
+                        ..Default::default()
+                    },
+                    &mut notes,
+                    fetched,
+                    local,
+                )
+                .await?;
                 (
-                    unparse_entities(
+                    unparse(
                         &animation.caption.clone().unwrap_or("".to_string()), animation.caption_entities.clone()
                     ),
-                    vec![core::Attachment {
-                        file: attachment,
-                        spoilered: animation.has_media_spoiler,
-                        filename: String::new(),
-                    }],
+                    attachments,
                 )
             }
 
             // plain files
             MediaKind::Document(document) => {
-                let file = bot.get_file(&document.document.file.id).await?;
-                let attachment = to_core_file(bot.clone(), &file).await?;
+                let attachments = to_core_attachments(
+                    bot.clone(),
+                    &document.document.file,
+                    "file",
+                    AttachmentInfo {
+                        filename: document.document.file_name.clone(),
+                        mime_type: document.document.mime_type.as_ref().map(ToString::to_string),
+                        ..Default::default()
+                    },
+                    &mut notes,
+                    fetched,
+                    local,
+                )
+                .await?;
                 (
-                    unparse_entities(
+                    unparse(
                         &document.caption.clone().unwrap_or("".to_string()), document.caption_entities.clone()
                     ),
-                    vec![core::Attachment {
-                        file: attachment,
-                        spoilered: false,
-                        filename: String::new(),
-                    }],
+                    attachments,
                 )
             }
 
             MediaKind::Sticker(sticker) => {
                 let sticker = &sticker.sticker;
-                match sticker.flags.is_animated {
-                    false => {
-                        // raster or video sticker
-                        let file = bot.get_file(&sticker.file.id).await?;
-                        let attachment = to_core_file(bot.clone(), &file).await?;
-                        let set_name = match sticker.set_name.clone() {
-                            Some(slug) => {
-                                let set = bot.get_sticker_set(&slug).await?;
-                                // technically discord sugar, but its fine for now
-                                format!("[{}](<https://t.me/addstickers/{}>)", &set.title, &slug)
-                            }
-                            None => "Unknown set".to_owned(),
-                        };
-                        (
-                            format!(
-                                "_{} sticker from {}_",
-                                &sticker.emoji.clone().unwrap_or("?".to_owned()),
-                                &set_name,
-                            ),
-                            vec![core::Attachment {
-                                file: attachment,
-                                spoilered: false,
-                                filename: String::new(),
-                            }],
-                        )
+                let pack = match sticker.set_name.clone() {
+                    Some(slug) => {
+                        let set = bot.get_sticker_set(&slug).await?;
+                        // technically discord sugar, but its fine for now
+                        Some(format!("[{}](<https://t.me/addstickers/{}>)", &set.title, &slug))
                     }
-                    true => ("[Animated sticker]".to_owned(), vec![]),
-                }
+                    None => None,
+                };
+
+                let attachments =
+                    sticker_attachments(bot.clone(), sticker, group, &mut notes, fetched, local).await?;
+
+                let sticker = core::Sticker {
+                    name: None,
+                    emoji: sticker.emoji.clone(),
+                    pack,
+                };
+                let content = sticker.to_markdown(attachments.is_empty());
+                kind = core::ContentKind::Sticker(sticker);
+
+                (content, attachments)
+            }
+
+            MediaKind::Poll(poll) => {
+                let poll = to_core_poll(&poll.poll);
+                let content = poll.to_markdown();
+                kind = core::ContentKind::Poll(poll);
+                (content, vec![])
             }
 
             _ => ("[Unknown media kind]".to_owned(), {
@@ -314,9 +313,13 @@ pub async fn to_core_message(
         MessageKind::Dice(die) => (
             {
                 // dice.emoji implements serde::Serialize, so we can just use it
-                let value = serialize_die_value(die.dice.clone());
+                let dice = core::Dice {
+                    emoji: dice_emoji(&die.dice.emoji).to_owned(),
+                    value: die.dice.value,
+                };
+                kind = core::ContentKind::Dice(dice);
 
-                format!("_{} rolled a die!_\n{}", core_author.full_name(Some(0)), value)
+                format!("_{} rolled a die!_", core_author.full_name(&group.templates.author, 0))
             },
             vec![],
         ),
@@ -342,7 +345,7 @@ pub async fn to_core_message(
         MessageKind::Pinned(pinned) => (
             format!(
                 "_{} pinned a message:_\n{}",
-                core_author.full_name(Some(0)),
+                core_author.full_name(&group.templates.author, 0),
                 pinned
                     .pinned
                     .as_ref()
@@ -360,33 +363,27 @@ pub async fn to_core_message(
         }),
     };
 
-    let forwarded_header = match m.forward_origin() {
+    let origin = match m.forward_origin() {
         Some(MessageOrigin::User {
             date: _,
             sender_user,
-        }) => {
-            format!("*Forwarded from {}*", &sender_user.full_name())
-        }
+        }) => Some(sender_user.full_name()),
 
         Some(MessageOrigin::HiddenUser {
             date: _,
             sender_user_name,
-        }) => format!("*Forwarded from {}*", &sender_user_name),
+        }) => Some(sender_user_name.clone()),
 
         Some(MessageOrigin::Chat {
             date: _,
             sender_chat: chat,
             author_signature,
-        }) => match author_signature {
-            Some(signature) => format!(
-                "*Forwarded from {} ({})*",
-                chat.title().unwrap_or("an unknown chat"),
-                &signature
-            ),
-            None => format!(
-                "*Forwarded from {}*",
-                chat.title().unwrap_or("an unknown chat")
-            ),
+        }) => {
+            let title = chat.title().unwrap_or("an unknown chat");
+            Some(match author_signature {
+                Some(signature) => format!("{title} ({signature})"),
+                None => title.to_owned(),
+            })
         },
 
         Some(MessageOrigin::Channel {
@@ -394,36 +391,167 @@ pub async fn to_core_message(
             chat,
             message_id: _,
             author_signature,
-        }) => match author_signature {
-            Some(signature) => format!(
-                "*Forwarded from {} ({})*",
-                chat.title().unwrap_or("an unknown channel"),
-                &signature
-            ),
-            None => format!(
-                "*Forwarded from {}*",
-                chat.title().unwrap_or("an unknown channel")
-            ),
+        }) => {
+            let title = chat.title().unwrap_or("an unknown channel");
+            Some(match author_signature {
+                Some(signature) => format!("{title} ({signature})"),
+                None => title.to_owned(),
+            })
         },
 
-        _ => "".to_owned(),
+        _ => None,
+    };
+
+    let forwarded_header = match origin {
+        Some(origin) => core::fill_template(&group.templates.forward, &[("origin", &origin)]),
+        None => String::new(),
+    };
+
+    attachments.extend(emoji_attachments);
+
+    let content = [forwarded_header, content, notes.join("\n")]
+        .join("\n")
+        .trim()
+        .to_owned();
+
+    let mut message =
+        core::Message::new(core_author, content, attachments, in_reply_to, reply_author).await;
+    message.kind = kind;
+
+    Ok(message)
+}
+
+/// Downloads a sticker for other platforms, converting animated ones if the group wants that.
+async fn sticker_attachments(
+    bot: Bot,
+    sticker: &types::Sticker,
+    group: &GroupConfig,
+    notes: &mut Vec<String>,
+    fetched: &mut Fetched,
+    local: bool,
+) -> color_eyre::Result<Vec<core::Attachment>> {
+    let converted = if group.convert_stickers && !sticker.is_static() {
+        match convert_sticker(bot.clone(), sticker, local).await {
+            Ok(attachment) => Some(attachment),
+            Err(report) => {
+                warn!(?report, "Failed to convert sticker");
+                None
+            }
+        }
+    } else {
+        None
     };
 
-    let content = [forwarded_header, content].join("\n").trim().to_owned();
+    Ok(match converted {
+        Some(attachment) => vec![attachment],
+        // TGS stickers are useless anywhere else without conversion
+        None if sticker.flags.is_animated => vec![],
+        None => {
+            to_core_attachments(
+                bot.clone(),
+                &sticker.file,
+                "sticker",
+                AttachmentInfo {
+                    mime_type: Some(
+                        if sticker.flags.is_video { "video/webm" } else { "image/webp" }
+                            .to_owned(),
+                    ),
+                    width: Some(sticker.width.into()),
+                    height: Some(sticker.height.into()),
+                    ..Default::default()
+                },
+                notes,
+                fetched,
+                local,
+            )
+            .await?
+        }
+    })
+}
+
+/// Maps the message's custom emoji to the group's Discord emoji, and downloads the ones without a
+/// counterpart as images if the group wants that.
+async fn custom_emoji(
+    bot: Bot,
+    m: &Message,
+    group: &GroupConfig,
+    notes: &mut Vec<String>,
+    fetched: &mut Fetched,
+    local: bool,
+) -> (HashMap<String, String>, Vec<core::Attachment>) {
+    let ids = m
+        .entities()
+        .or(m.caption_entities())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|entity| match &entity.kind {
+            MessageEntityKind::CustomEmoji { custom_emoji_id } => Some(custom_emoji_id.clone()),
+            _ => None,
+        });
+    let mappings = group.discord.as_ref().map_or(&[][..], |dsc| &dsc.custom_emoji);
+
+    let mut emoji = HashMap::new();
+    let mut unmapped = vec![];
+    for id in ids {
+        match mappings.iter().find(|mapping| mapping.telegram == id) {
+            Some(mapping) => {
+                emoji.insert(id, mapping.discord.clone());
+            }
+            None if !unmapped.contains(&id) => unmapped.push(id),
+            None => (),
+        }
+    }
+
+    // the fallback emoji stays in the text, so any that can't be attached are only left as that
+    let mut attachments = vec![];
+    if group.custom_emoji_images && !unmapped.is_empty() {
+        unmapped.truncate(CUSTOM_EMOJI_IMAGE_LIMIT);
+        let stickers = match bot.get_custom_emoji_stickers(unmapped).await {
+            Ok(stickers) => stickers,
+            Err(why) => {
+                warn!(?why, "Failed to get custom emoji stickers");
+                vec![]
+            }
+        };
+        for sticker in stickers {
+            match sticker_attachments(bot.clone(), &sticker, group, notes, fetched, local).await {
+                Ok(sticker) => attachments.extend(sticker),
+                Err(why) => warn!(?why, "Failed to attach custom emoji"),
+            }
+        }
+    }
+
+    (emoji, attachments)
+}
 
-    Ok(core::Message::new(core_author, content, attachments, in_reply_to, reply_author).await)
+pub fn to_core_poll(poll: &types::Poll) -> core::Poll {
+    core::Poll {
+        question: poll.question.clone(),
+        options: poll
+            .options
+            .iter()
+            .map(|option| core::PollOption {
+                text: option.text.clone(),
+                votes: Some(option.voter_count.into()),
+            })
+            .collect(),
+        multiple_answers: poll.allows_multiple_answers,
+        duration: poll.open_period.map(|period| period.duration()),
+        closed: poll.is_closed,
+    }
 }
 
 #[instrument(skip(bot))]
-async fn to_core_author(bot: Bot, author: &types::User) -> color_eyre::Result<core::Author> {
+async fn to_core_author(
+    bot: Bot,
+    author: &types::User,
+    local: bool,
+) -> color_eyre::Result<core::Author> {
     let photos = bot.get_user_profile_photos(author.id).await?;
     let photo = photos.photos.first();
 
     let core_file = match photo {
-        Some(photo) => match photo_to_core_file(bot, photo).await {
-            Ok(file) => Some(file),
-            Err(_) => None,
-        },
+        Some(photo) => photo_to_core_file(bot, photo, local).await.ok(),
         None => None,
     };
 
@@ -438,17 +566,113 @@ async fn to_core_author(bot: Bot, author: &types::User) -> color_eyre::Result<co
     })
 }
 
-pub async fn photo_to_core_file(bot: Bot, photo: &[PhotoSize]) -> color_eyre::Result<TempFile> {
+pub async fn photo_to_core_file(
+    bot: Bot,
+    photo: &[PhotoSize],
+    local: bool,
+) -> color_eyre::Result<TempFile> {
     if let Some(photo) = photo.last() {
         let file = bot.get_file(&photo.file.id).await?;
-        to_core_file(bot, &file).await
+        to_core_file(bot, &file, local).await
     } else {
         Err(color_eyre::eyre::eyre!("No photo found"))
     }
 }
 
+/// What was fetched from Telegram to parse a message, so parsing its edits only fetches what changed.
+#[derive(Debug, Default)]
+pub struct Fetched {
+    author: Option<PartialAuthor>,
+    /// Downloaded files by their `file_unique_id`.
+    files: HashMap<String, TempFile>,
+    /// Files of the version before, which the current one takes over if it still has them.
+    previous_files: HashMap<String, TempFile>,
+}
+
+impl Fetched {
+    /// Prepares for parsing an edit of the message this was fetched for.
+    pub fn for_edit(self) -> Self {
+        Self {
+            author: self.author,
+            files: HashMap::new(),
+            previous_files: self.files,
+        }
+    }
+
+    /// Downloads a file, unless the version before already had it.
+    async fn file(&mut self, bot: Bot, meta: &FileMeta, local: bool) -> color_eyre::Result<TempFile> {
+        let file = match self.previous_files.remove(&meta.unique_id) {
+            Some(file) => file,
+            None => {
+                let file = bot.get_file(&meta.id).await?;
+                to_core_file(bot, &file, local).await?
+            }
+        };
+        self.files.insert(meta.unique_id.clone(), file.try_clone().await?);
+        Ok(file)
+    }
+}
+
+/// Metadata Telegram gives us about a file, carried over to the core attachment.
+#[derive(Default)]
+struct AttachmentInfo {
+    filename: Option<String>,
+    mime_type: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    duration: Option<Duration>,
+    spoilered: bool,
+    voice: bool,
+}
+
+/// Downloads a file from Telegram into a core attachment.
+///
+/// Files over the cloud Bot API's download limit can't be fetched at all, so they get a note instead,
+/// naming them by `label` if they have no filename.
+async fn to_core_attachments(
+    bot: Bot,
+    meta: &FileMeta,
+    label: &str,
+    info: AttachmentInfo,
+    notes: &mut Vec<String>,
+    fetched: &mut Fetched,
+    local: bool,
+) -> color_eyre::Result<Vec<core::Attachment>> {
+    let size = u64::from(meta.size);
+    if !local && size > DOWNLOAD_LIMIT {
+        debug!(size, "file is over the download limit, skipping");
+        notes.push(core::too_large_note(
+            info.filename.as_deref().unwrap_or(label),
+            size,
+        ));
+        return Ok(vec![]);
+    }
+
+    let attachment = fetched.file(bot, meta, local).await?;
+
+    Ok(vec![core::Attachment {
+        file: attachment,
+        filename: info.filename.unwrap_or_default(),
+        spoilered: info.spoilered,
+        mime_type: info.mime_type,
+        width: info.width,
+        height: info.height,
+        duration: info.duration,
+        description: None,
+        voice: info.voice,
+        waveform: None,
+    }])
+}
+
+/// Fetches a Telegram file into a temporary file.
+///
+/// A local Bot API server hands out paths on its own filesystem, so those are copied directly.
 #[instrument(skip(bot))]
-pub async fn to_core_file(bot: Bot, file: &teloxide::types::File) -> color_eyre::Result<TempFile> {
+pub async fn to_core_file(
+    bot: Bot,
+    file: &teloxide::types::File,
+    local: bool,
+) -> color_eyre::Result<TempFile> {
     let extension = Path::new(&file.path)
         .extension()
         .and_then(|ext| ext.to_str())
@@ -456,21 +680,34 @@ pub async fn to_core_file(bot: Bot, file: &teloxide::types::File) -> color_eyre:
     let mut tmpfile =
         async_tempfile::TempFile::new_with_name(&format!("{}.{}", &file.unique_id, extension))
             .await?;
-    // let path = core::get_tmp_dir()?.join(format!("{}.{}", &file.unique_id, extension));
-    // let mut dst = fs::File::create(&path.file_path()).await?;
-    bot.download_file(&file.path, &mut tmpfile).await?;
-
-    // Ok(core::File {
-    //     name: file.path.replace('/', "_"),
-    //     path: path.into_path(),
-    // })
+
+    if local {
+        let mut src = tokio::fs::File::open(&file.path).await?;
+        tokio::io::copy(&mut src, &mut tmpfile).await?;
+        tmpfile.flush().await?;
+    } else {
+        bot.download_file(&file.path, &mut tmpfile).await?;
+    }
+
     Ok(tmpfile)
 }
 
-fn location_url(location: &Location) -> String {
-    format!("https://www.google.com/maps/search/?api=1&query={}%2C{}", location.latitude, location.longitude)
-}
+/// Live period Telegram uses for live locations shared until they're stopped.
+const INDEFINITE_LIVE_PERIOD: u32 = 0x7FFF_FFFF;
+
+/// Most custom emoji attached as images to a single message.
+const CUSTOM_EMOJI_IMAGE_LIMIT: usize = 5;
 
-fn location_url_with_place_id(location: &Location, place_id: &str) -> String {
-    format!("https://www.google.com/maps/search/?api=1&query={}%2C{}&query_place_id={}", location.latitude, location.longitude, place_id)
+/// Converts a location, working out until when it's live from the time it was sent.
+fn to_core_location(location: &Location, sent: DateTime<Utc>) -> core::Location {
+    let live = location.live_period.map(|period| match period.seconds() {
+        INDEFINITE_LIVE_PERIOD => core::LiveLocation::Indefinite,
+        _ => core::LiveLocation::Until(sent + period.chrono_duration()),
+    });
+
+    core::Location {
+        latitude: location.latitude,
+        longitude: location.longitude,
+        live,
+    }
 }
@@ -0,0 +1,98 @@
+use std::{path::Path, process::Stdio};
+
+use async_tempfile::TempFile;
+use color_eyre::{eyre::eyre, Result};
+use teloxide::{prelude::*, types::Sticker};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::*;
+
+use crate::core;
+
+use super::to_core_file;
+
+/// Converts an animated (TGS) or video (WEBM) sticker into a GIF that Discord plays inline.
+///
+/// Conversions are kept in a cache directory keyed by the sticker's `file_unique_id`, so each
+/// sticker is only converted once.
+#[instrument(skip(bot, sticker), fields(unique_id = sticker.file.unique_id))]
+pub async fn convert_sticker(bot: Bot, sticker: &Sticker, local: bool) -> Result<core::Attachment> {
+    let cache_dir = std::env::temp_dir().join("oxibridge-stickers");
+    tokio::fs::create_dir_all(&cache_dir).await?;
+
+    let cached = cache_dir.join(format!("{}.gif", sticker.file.unique_id));
+    if tokio::fs::try_exists(&cached).await? {
+        debug!("using cached conversion");
+    } else {
+        let file = bot.get_file(&sticker.file.id).await?;
+        let source = to_core_file(bot, &file, local).await?;
+
+        // convert into a scratch file next to the cache, and only move it in once it's complete,
+        // so failed or concurrent conversions never leave a partial GIF behind
+        let output = TempFile::new_in(cache_dir.as_path()).await?;
+        if sticker.flags.is_animated {
+            tgs_to_gif(source.file_path(), output.file_path()).await?;
+        } else {
+            webm_to_gif(source.file_path(), output.file_path()).await?;
+        }
+        tokio::fs::rename(output.file_path(), &cached).await?;
+        debug!("converted sticker");
+    }
+
+    let mut file = TempFile::new().await?;
+    let mut cached = tokio::fs::File::open(&cached).await?;
+    tokio::io::copy(&mut cached, &mut file).await?;
+    file.flush().await?;
+
+    Ok(core::Attachment {
+        file,
+        filename: "sticker.gif".to_owned(),
+        spoilered: false,
+        mime_type: Some("image/gif".to_owned()),
+        width: Some(sticker.width.into()),
+        height: Some(sticker.height.into()),
+        duration: None,
+        description: sticker.emoji.clone(),
+        voice: false,
+        waveform: None,
+    })
+}
+
+/// Renders a TGS (gzipped Lottie) sticker with `lottieconverter`.
+async fn tgs_to_gif(input: &Path, output: &Path) -> Result<()> {
+    let status = Command::new("lottieconverter")
+        .arg(input)
+        .arg(output)
+        .args(["gif", "256x256", "30"])
+        .stdin(Stdio::null())
+        .status()
+        .await?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(eyre!("lottieconverter exited with {status}")),
+    }
+}
+
+/// Converts a WEBM video sticker to GIF with `ffmpeg`, keeping its transparency.
+async fn webm_to_gif(input: &Path, output: &Path) -> Result<()> {
+    let status = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-c:v", "libvpx-vp9", "-i"])
+        .arg(input)
+        .args([
+            "-vf",
+            "fps=30,split[a][b];[a]palettegen=reserve_transparent=1[p];[b][p]paletteuse",
+            "-loop",
+            "0",
+            "-f",
+            "gif",
+        ])
+        .arg(output)
+        .stdin(Stdio::null())
+        .status()
+        .await?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(eyre!("ffmpeg exited with {status}")),
+    }
+}
@@ -1,73 +1,122 @@
 // Port of the markdown.unparse function from Pyrogram.
 // https://github.com/pyrogram/pyrogram/blob/master/pyrogram/parser/markdown.py#L119
 
+use std::collections::HashMap;
+
 use teloxide::types::{MessageEntity, MessageEntityKind};
 
 use super::entities::StringWithEntities;
 
-fn unparse_entities_impl(text: StringWithEntities) -> String {
-  let mut entities_offsets: Vec<(&str, usize)> = vec![];
+/// Markdown to insert into the text for an entity.
+struct Tag {
+  offset: usize,
+  closing: bool,
+  /// Index of the entity it belongs to, to nest tags properly.
+  entity: usize,
+  text: String,
+  /// How much of the text the tag takes the place of.
+  replaces: usize,
+}
+
+impl Tag {
+  fn sort_key(&self) -> (usize, u8, usize) {
+    // tags closing at an offset go before ones opening there, and close in reverse order.
+    // replacing tags go last, so they're spliced in before anything is inserted at their offset
+    match (self.closing, self.replaces) {
+      (true, _) => (self.offset, 0, usize::MAX - self.entity),
+      (false, 0) => (self.offset, 1, self.entity),
+      (false, _) => (self.offset, 2, self.entity),
+    }
+  }
+}
+
+fn unparse_entities_impl(text: StringWithEntities, custom_emoji: &HashMap<String, String>) -> String {
+  let mut tags: Vec<Tag> = vec![];
 
-  for entity in text.1 {
+  for (i, entity) in text.1.into_iter().enumerate() {
     let start = entity.offset;
-    let end = start + entity.length;
-
-    let tags = match entity.kind {
-      MessageEntityKind::Bold => Some(("**", "**")),
-      MessageEntityKind::Italic => Some(("_", "_")),
-      MessageEntityKind::Strikethrough => Some(("~~", "~~")),
-      MessageEntityKind::Underline => Some(("__", "__")),
-      MessageEntityKind::Code => Some(("``", "``")),
-      MessageEntityKind::Pre { language } => match language {
-        // TODO: consider language
-        Some(_) => Some(("```", "```")),
-        None => Some(("```", "```"))
+    let end = (start + entity.length).min(text.0.len());
+    if start >= end {
+      continue;
+    }
+
+    let (start_tag, end_tag) = match entity.kind {
+      MessageEntityKind::Bold => ("**".to_owned(), "**".to_owned()),
+      MessageEntityKind::Italic => ("_".to_owned(), "_".to_owned()),
+      MessageEntityKind::Strikethrough => ("~~".to_owned(), "~~".to_owned()),
+      MessageEntityKind::Underline => ("__".to_owned(), "__".to_owned()),
+      MessageEntityKind::Spoiler => ("||".to_owned(), "||".to_owned()),
+      MessageEntityKind::Code => ("``".to_owned(), "``".to_owned()),
+      MessageEntityKind::Pre { language } => (
+        format!("```{}\n", language.unwrap_or_default()),
+        "\n```".to_owned(),
+      ),
+      MessageEntityKind::TextLink { url } => ("[".to_owned(), format!("]({url})")),
+      // Discord can't mention Telegram users, so link to their profile if it's public
+      MessageEntityKind::TextMention { user } => match user.tme_url() {
+        Some(url) => ("[".to_owned(), format!("]({url})")),
+        None => continue,
       },
-      // FIXME: won't work correctly with newlines. but bleh
-      MessageEntityKind::Blockquote => Some(("> ", "")),
-      MessageEntityKind::Spoiler => Some(("||", "||")),
-      // TODO: text links, text mentions
-      _ => None,
+      MessageEntityKind::Blockquote => {
+        // every line needs its own marker
+        tags.push(Tag { offset: start, closing: false, entity: i, text: "> ".to_owned(), replaces: 0 });
+        for (offset, _) in text.0[start..end - 1]
+          .iter()
+          .enumerate()
+          .filter(|(_, &c)| c == '\n' as u16)
+        {
+          tags.push(Tag { offset: start + offset + 1, closing: false, entity: i, text: "> ".to_owned(), replaces: 0 });
+        }
+        continue;
+      }
+      MessageEntityKind::CustomEmoji { custom_emoji_id } => {
+        // otherwise, the text already has the emoji it's based on
+        if let Some(emoji) = custom_emoji.get(&custom_emoji_id) {
+          tags.push(Tag { offset: start, closing: false, entity: i, text: emoji.clone(), replaces: end - start });
+        }
+        continue;
+      }
+      // Telegram highlights these on its own, and so does Discord for URLs
+      MessageEntityKind::Mention
+      | MessageEntityKind::Hashtag
+      | MessageEntityKind::Cashtag
+      | MessageEntityKind::BotCommand
+      | MessageEntityKind::Url
+      | MessageEntityKind::Email
+      | MessageEntityKind::PhoneNumber => continue,
     };
 
-    if let Some((start_tag, end_tag)) = tags {
-      entities_offsets.push((start_tag, start));
-      if !end_tag.is_empty() {
-        entities_offsets.push((end_tag, end));
-      }
-    }
+    tags.push(Tag { offset: start, closing: false, entity: i, text: start_tag, replaces: 0 });
+    tags.push(Tag { offset: end, closing: true, entity: i, text: end_tag, replaces: 0 });
   }
 
-  let mut entities_enumerated: Vec<_> = entities_offsets.iter().enumerate().collect();
-  entities_enumerated.sort_by_key(|x| (x.1.1, x.0));
-  entities_enumerated.reverse();
-  let entities_offsets: Vec<_> = entities_enumerated.iter().map(|x| x.1).collect();
+  // insert from the end, so earlier offsets stay valid
+  tags.sort_by_key(Tag::sort_key);
 
   let mut text = text.0;
 
-  for (entity, offset) in entities_offsets {
-    let mut entity: Vec<u16> = entity.encode_utf16().collect();
-    let offset = *offset;
-    let mut text_left: Vec<u16> = text[..offset].to_vec();
-    let mut text_right: Vec<u16> = text[offset..].to_vec();
-    text_left.append(&mut entity);
-    text_left.append(&mut text_right);
-
-    text = text_left;
+  for tag in tags.iter().rev() {
+    text.splice(tag.offset..tag.offset + tag.replaces, tag.text.encode_utf16());
   }
 
   String::from_utf16_lossy(&text)
 }
 
-pub fn unparse_entities(text: &str, entities: Vec<MessageEntity>) -> String {
-  unparse_entities_impl(StringWithEntities(text.encode_utf16().collect(), entities))
+/// Turns text with entities into Markdown, replacing custom emoji by ID where the map has them.
+pub fn unparse_entities(
+  text: &str,
+  entities: Vec<MessageEntity>,
+  custom_emoji: &HashMap<String, String>,
+) -> String {
+  unparse_entities_impl(StringWithEntities(text.encode_utf16().collect(), entities), custom_emoji)
 }
 
 #[cfg(test)]
 mod tests {
-  use teloxide::types::MessageEntity;
+  use teloxide::types::{MessageEntity, MessageEntityKind};
   use crate::telegram::entities::StringWithEntities;
   use super::unparse_entities_impl;
+  use std::collections::HashMap;
 
   fn string_with_entities(text: &str, entities: Vec<MessageEntity>) -> StringWithEntities {
     StringWithEntities(text.encode_utf16().collect(), entities)
@@ -80,7 +129,7 @@ mod tests {
     ]);
 
     assert_eq!(
-      unparse_entities_impl(string),
+      unparse_entities_impl(string, &HashMap::new()),
       "hello, **world**!".to_owned()
     );
   }
@@ -92,8 +141,85 @@ mod tests {
     ]);
 
     assert_eq!(
-      unparse_entities_impl(string),
+      unparse_entities_impl(string, &HashMap::new()),
       "hello, _world_!".to_owned()
     );
   }
+
+  #[test]
+  fn nests_tags_properly() {
+    let string = string_with_entities("hello, world!", vec![
+      MessageEntity::bold(7, 5),
+      MessageEntity::italic(7, 5)
+    ]);
+
+    assert_eq!(
+      unparse_entities_impl(string, &HashMap::new()),
+      "hello, **_world_**!".to_owned()
+    );
+  }
+
+  #[test]
+  fn unparses_text_links() {
+    let string = string_with_entities("see here", vec![
+      MessageEntity::text_link("https://itsvic.dev".parse().unwrap(), 4, 4)
+    ]);
+
+    assert_eq!(
+      unparse_entities_impl(string, &HashMap::new()),
+      "see [here](https://itsvic.dev/)".to_owned()
+    );
+  }
+
+  #[test]
+  fn unparses_code_blocks_with_language() {
+    let string = string_with_entities("fn main() {}", vec![
+      MessageEntity::pre(Some("rs".to_owned()), 0, 12)
+    ]);
+
+    assert_eq!(
+      unparse_entities_impl(string, &HashMap::new()),
+      "```rs\nfn main() {}\n```".to_owned()
+    );
+  }
+
+  #[test]
+  fn quotes_every_line() {
+    let string = string_with_entities("one\ntwo\nthree", vec![
+      MessageEntity { kind: MessageEntityKind::Blockquote, offset: 0, length: 7 }
+    ]);
+
+    assert_eq!(
+      unparse_entities_impl(string, &HashMap::new()),
+      "> one\n> two\nthree".to_owned()
+    );
+  }
+
+  #[test]
+  fn replaces_mapped_custom_emoji() {
+    let string = string_with_entities("hi 😺 🐶", vec![
+      MessageEntity::custom_emoji("1".to_owned(), 3, 2),
+      MessageEntity::custom_emoji("2".to_owned(), 6, 2)
+    ]);
+    let custom_emoji = HashMap::from([("1".to_owned(), "<:blobcat:42>".to_owned())]);
+
+    assert_eq!(
+      unparse_entities_impl(string, &custom_emoji),
+      "hi <:blobcat:42> 🐶".to_owned()
+    );
+  }
+
+  #[test]
+  fn replaces_custom_emoji_inside_other_tags() {
+    let string = string_with_entities("😺 hi", vec![
+      MessageEntity::custom_emoji("1".to_owned(), 0, 2),
+      MessageEntity::bold(0, 5)
+    ]);
+    let custom_emoji = HashMap::from([("1".to_owned(), "<:x:9>".to_owned())]);
+
+    assert_eq!(
+      unparse_entities_impl(string, &custom_emoji),
+      "**<:x:9> hi**".to_owned()
+    );
+  }
 }